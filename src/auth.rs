@@ -0,0 +1,71 @@
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use tracing::{debug, warn};
+
+use crate::config::ServerConfig;
+
+/// Whether `password` is right for `username`, checked against the argon2
+/// hash in `users`. Hashing is slow on purpose, so it is kept off the
+/// async workers.
+pub async fn verify(config: &ServerConfig, username: &str, password: &str) -> bool {
+    let Some(hash) = config.users().get(username).cloned() else {
+        debug!("No user {username}");
+        return false;
+    };
+    let password = password.to_string();
+    let username = username.to_string();
+
+    let verified = tokio::task::spawn_blocking(move || {
+        let hash = match PasswordHash::new(&hash) {
+            Ok(v) => v,
+            Err(_e) => {
+                warn!("Couldn't parse the password hash of {username}");
+                debug!("Error: {_e}");
+                return false;
+            }
+        };
+
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+    .await;
+
+    verified.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    use super::*;
+
+    fn config(hash: &str) -> ServerConfig {
+        toml::from_str(&format!(
+            "hostname = \"localhost\"\nplugins = []\n[lists]\n[users]\n\"alice@example.com\" = {hash:?}\n"
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn verifies_passwords() {
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let hash = Argon2::default()
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        let config = config(&hash);
+
+        assert!(verify(&config, "alice@example.com", "hunter2").await);
+        assert!(!verify(&config, "alice@example.com", "hunter3").await);
+        assert!(!verify(&config, "alice@example.com", "").await);
+        assert!(!verify(&config, "bob@example.com", "hunter2").await);
+        // Usernames are compared as they are
+        assert!(!verify(&config, "Alice@example.com", "hunter2").await);
+    }
+
+    #[tokio::test]
+    async fn refuses_broken_hashes() {
+        assert!(!verify(&config("hunter2"), "alice@example.com", "hunter2").await);
+        assert!(!verify(&config(""), "alice@example.com", "").await);
+    }
+}
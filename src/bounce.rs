@@ -0,0 +1,284 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{BounceAction, ServerConfig},
+    dsn,
+    message::{Header, Message},
+    queue,
+};
+
+/// Serializes updates to the bounce files, bounces can arrive on several
+/// connections at once.
+static BOUNCES: Mutex<()> = Mutex::const_new(());
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BounceState {
+    #[serde(default)]
+    members: HashMap<String, MemberState>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct MemberState {
+    score: u32,
+    last_bounce: u64,
+    disabled: bool,
+}
+
+fn bare(address: &str) -> &str {
+    address.trim_start_matches('<').trim_end_matches('>')
+}
+
+fn state_file(config: &ServerConfig, list: &str) -> PathBuf {
+    config
+        .queue()
+        .spool()
+        .join("bounces")
+        .join(format!("{list}.toml"))
+}
+
+async fn load(config: &ServerConfig, list: &str) -> Result<BounceState> {
+    let file = state_file(config, list);
+
+    if !tokio::fs::try_exists(&file).await? {
+        return Ok(BounceState::default());
+    }
+
+    Ok(toml::from_str(&tokio::fs::read_to_string(file).await?)?)
+}
+
+async fn save(config: &ServerConfig, list: &str, state: &BounceState) -> Result<()> {
+    let file = state_file(config, list);
+
+    if let Some(parent) = file.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    queue::write_atomic(&file, toml::to_string(state)?.as_bytes()).await
+}
+
+/// The envelope sender for a list delivery to `member`, e.g.
+/// `board-bounces+foo=example.com@example.com` for `foo@example.com` on
+/// `board@example.com`.
+pub fn verp_sender(list: &str, member: &str) -> String {
+    let (local, domain) = list.rsplit_once('@').unwrap_or((list, ""));
+    let member = bare(member).replacen('@', "=", 1);
+
+    format!("<{local}-bounces+{member}@{domain}>")
+}
+
+/// Recognises a VERP address generated by [`verp_sender`], returning the
+/// list and the member it belongs to.
+pub fn parse_verp(config: &ServerConfig, recipient: &str) -> Option<(String, String)> {
+    let recipient = bare(recipient);
+    let (local, domain) = recipient.rsplit_once('@')?;
+    let (list_local, member) = local.split_once("-bounces+")?;
+    let (list, settings) = config.list(&format!("{list_local}@{domain}"))?;
+
+    if !settings.verp() {
+        return None;
+    }
+
+    // Only the domain can't contain `=`, so the last one was the `@`
+    let (member_local, member_domain) = member.rsplit_once('=')?;
+
+    Some((list.to_string(), format!("{member_local}@{member_domain}")))
+}
+
+/// Whether a message sent to a VERP address reports a failure, as opposed
+/// to e.g. a delay warning. Anything but a DSN only counts if its text has a
+/// permanent status code, e.g. `5.1.1`, as anyone can send to the address.
+pub fn is_failure(data: &[u8]) -> bool {
+    let message = Message::parse(data);
+    let reports: Vec<&Message> = message
+        .walk()
+        .into_iter()
+        .filter(|x| x.content_type().mime_type == "message/delivery-status")
+        .collect();
+
+    if reports.is_empty() {
+        return message
+            .walk()
+            .iter()
+            .filter(|x| x.content_type().mime_type == "text/plain")
+            .filter_map(|x| x.text())
+            .any(|x| dsn::status_code(&x).is_some_and(|x| x.starts_with('5')));
+    }
+
+    // Per-recipient fields come in groups of their own, which parse as one
+    // header with a few empty lines in it (RFC 3464 §2.1)
+    reports.iter().filter_map(|x| x.decoded()).any(|x| {
+        Header::parse(&x)
+            .fields
+            .iter()
+            .any(|x| x.is("Action") && x.value().eq_ignore_ascii_case("failed"))
+    })
+}
+
+/// Adds a bounce to the score of `member`, disabling or unsubscribing them
+/// once it reaches the threshold of the list.
+pub async fn record(config: &ServerConfig, list_address: &str, member: &str) {
+    match record_bounce(config, list_address, bare(member)).await {
+        Ok(_) => {}
+        Err(_e) => {
+            warn!("Couldn't record bounce from {member} on {list_address}");
+            debug!("Error: {_e}");
+        }
+    }
+}
+
+async fn record_bounce(config: &ServerConfig, list_address: &str, member: &str) -> Result<()> {
+    let Some(list) = config.lists.get(list_address) else {
+        return Ok(());
+    };
+    let Some(options) = &list.bounces else {
+        return Ok(());
+    };
+
+    let _lock = BOUNCES.lock().await;
+    let mut state = load(config, list_address).await?;
+    let entry = state.members.entry(member.to_string()).or_default();
+    let now = queue::now();
+
+    if now.saturating_sub(entry.last_bounce) > options.reset_after() * DAY {
+        entry.score = 0;
+    }
+    // At most one bounce a day counts, a single outage can cause many
+    if entry.score == 0 || now / DAY != entry.last_bounce / DAY {
+        entry.score += 1;
+    }
+    entry.last_bounce = now;
+
+    info!(
+        "{member} on {list_address} bounced, score {}/{}",
+        entry.score,
+        options.threshold()
+    );
+
+    if entry.score >= options.threshold() && !entry.disabled {
+        if options.action.unwrap_or_default() == BounceAction::Unsubscribe
+            && list.members.remove_member(member).await?
+        {
+            info!("Unsubscribed {member} from {list_address} after too many bounces");
+            state.members.remove(member);
+        } else {
+            info!("Disabled {member} on {list_address} after too many bounces");
+            entry.disabled = true;
+        }
+    }
+
+    save(config, list_address, &state).await
+}
+
+/// Members of `list` that have been disabled because of bounces.
+pub async fn disabled(config: &ServerConfig, list: &str) -> HashSet<String> {
+    let _lock = BOUNCES.lock().await;
+
+    match load(config, list).await {
+        Ok(state) => state
+            .members
+            .into_iter()
+            .filter(|(_, x)| x.disabled)
+            .map(|(member, _)| member)
+            .collect(),
+        Err(_e) => {
+            warn!("Couldn't read bounce state for {list}");
+            debug!("Error: {_e}");
+            HashSet::new()
+        }
+    }
+}
+
+/// Drops the members that have been disabled because of bounces.
+pub async fn active_members(
+    config: &ServerConfig,
+    list: &str,
+    members: Vec<String>,
+) -> Vec<String> {
+    let disabled = disabled(config, list).await;
+
+    members
+        .into_iter()
+        .filter(|x| !disabled.contains(bare(x)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_verp_addresses() {
+        let config: ServerConfig = toml::from_str(
+            "hostname = \"example.com\"\nplugins = []\n\
+             [lists.\"board@example.com\".Local]\nmembers = []\n\
+             [lists.\"board@example.com\".bounces]\n\
+             [lists.\"plain@example.com\".Local]\nmembers = []\n",
+        )
+        .unwrap();
+
+        let sender = verp_sender("board@example.com", "<a=b@example.net>");
+        assert_eq!(sender, "<board-bounces+a=b=example.net@example.com>");
+        assert_eq!(
+            parse_verp(&config, &sender),
+            Some((
+                "board@example.com".to_string(),
+                "a=b@example.net".to_string()
+            ))
+        );
+
+        assert_eq!(
+            parse_verp(&config, "<board-bounces+a=example.net@EXAMPLE.com>"),
+            Some(("board@example.com".to_string(), "a@example.net".to_string()))
+        );
+
+        // Only lists using VERP have these addresses
+        let sender = verp_sender("plain@example.com", "foo@example.net");
+        assert_eq!(parse_verp(&config, &sender), None);
+        let sender = verp_sender("other@example.com", "foo@example.net");
+        assert_eq!(parse_verp(&config, &sender), None);
+        assert_eq!(parse_verp(&config, "<board@example.com>"), None);
+        assert_eq!(parse_verp(&config, "<board-bounces+foo@example.com>"), None);
+    }
+
+    fn report(action: &str) -> Vec<u8> {
+        format!(
+            "Content-Type: multipart/report; report-type=delivery-status; boundary=b\r\n\r\n\
+             --b\r\nContent-Type: text/plain\r\n\r\nSee below, 5.1.1\r\n\
+             --b\r\nContent-Type: message/delivery-status\r\n\r\n\
+             Reporting-MTA: dns; example.net\r\n\r\n\
+             Final-Recipient: rfc822; foo@example.net\r\n\
+             Action: {action}\r\nStatus: 4.4.1\r\n\
+             --b--\r\n"
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn counts_only_failures() {
+        assert!(is_failure(&report("failed")));
+        assert!(is_failure(&report("Failed")));
+        assert!(!is_failure(&report("delayed")));
+
+        assert!(is_failure(
+            b"Subject: Undeliverable\r\n\r\n<foo@example.net>: 550 5.1.1 No such user\r\n"
+        ));
+        assert!(!is_failure(
+            b"Subject: Undeliverable\r\n\r\n<foo@example.net>: 451 4.4.1 Try again\r\n"
+        ));
+        assert!(!is_failure(
+            b"Subject: Unsubscribe\r\n\r\nPlease remove me\r\n"
+        ));
+        assert!(!is_failure(
+            b"Content-Type: text/html\r\n\r\n<p>550 5.1.1 No such user</p>\r\n"
+        ));
+    }
+}
@@ -1,7 +1,26 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
 pub struct Cli {
     #[arg(short = 'c', long = "config")]
     pub config: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Decide on posts held for moderation
+    #[command(subcommand)]
+    Moderate(Moderate),
+}
+
+#[derive(Subcommand)]
+pub enum Moderate {
+    /// Show every held post
+    List,
+    /// Send a held post to its list
+    Approve { token: String },
+    /// Throw a held post away
+    Reject { token: String },
 }
@@ -1,24 +1,142 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
-use smtp_proto::{Request, Response};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use smtp_proto::{
+    Request, Response, AUTH_LOGIN, AUTH_PLAIN, MAIL_BODY_8BITMIME, MAIL_BODY_BINARYMIME,
+    MAIL_SMTPUTF8,
+};
 use std::io::Result;
-use tokio::{io::AsyncWriteExt, net::TcpStream, time::sleep};
+use tokio::{net::TcpStream, time::timeout};
 use tracing::info;
 
-static CAPABILITIES: &'static [u8] = br#"250-Helu!
-250-SIZE 14680064
-250-STARTTLS
-250 ENHANCEDSTATUSCODES
-"#;
+/// RFC 5321 §4.5.3.2.7, how long we wait for the client's next command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How long the client gets to send an entire DATA payload.
+const DATA_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
-use crate::{config::ServerConfig, mail::Mail, stream::Stream};
+use crate::{
+    auth, bounce,
+    config::{domain_of, ServerConfig},
+    mail::{Body, Mail},
+    moderation,
+    posting::{self, Decision},
+    stream::Stream,
+    tls,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Connected,
+    Greeted,
+    InTransaction,
+    InData,
+}
+
+#[derive(Debug)]
+struct Session {
+    state: State,
+    sender: String,
+    declared_size: usize,
+    body: Body,
+    smtputf8: bool,
+    recipients: Vec<String>,
+    /// Who the client logged in as. Kept until the connection ends.
+    authenticated: Option<String>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            state: State::Connected,
+            sender: String::new(),
+            declared_size: 0,
+            body: Body::SevenBit,
+            smtputf8: false,
+            recipients: Vec::new(),
+            authenticated: None,
+        }
+    }
+
+    fn accepts(&self, request: &Request<String>) -> bool {
+        match request {
+            Request::StartTls | Request::Auth { .. } | Request::Mail { .. } => {
+                self.state == State::Greeted
+            }
+            Request::Rcpt { .. } => self.state == State::InTransaction,
+            Request::Data => self.state == State::InTransaction && !self.recipients.is_empty(),
+            _ => self.state != State::InData,
+        }
+    }
+
+    fn greet(&mut self) {
+        self.reset();
+        self.state = State::Greeted;
+    }
+
+    /// Aborts the current transaction without forgetting the greeting.
+    fn reset(&mut self) {
+        if self.state != State::Connected {
+            self.state = State::Greeted;
+        }
+        self.sender.clear();
+        self.declared_size = 0;
+        self.body = Body::SevenBit;
+        self.smtputf8 = false;
+        self.recipients.clear();
+    }
+
+    /// Whether the client may send to other domains, having logged in or
+    /// connected from a trusted network.
+    fn may_relay(&self, config: &ServerConfig, ip: IpAddr) -> bool {
+        self.authenticated.is_some() || config.is_trusted(ip)
+    }
+
+    /// The strictest size limit among the lists in this transaction.
+    fn max_message_size(&self, config: &ServerConfig) -> usize {
+        self.recipients
+            .iter()
+            .map(|x| config.max_message_size_for(x))
+            .min()
+            .unwrap_or(config.max_message_size())
+    }
+
+    fn take_mail(&mut self, data: Vec<u8>) -> Mail {
+        let mail = Mail {
+            sender: std::mem::take(&mut self.sender),
+            recipients: std::mem::take(&mut self.recipients),
+            data,
+            body: self.body,
+            smtputf8: self.smtputf8,
+            authenticated: self.authenticated.clone(),
+        };
+        self.reset();
+        mail
+    }
+}
 
 pub async fn handle_client(
-    _addr: SocketAddr,
+    addr: SocketAddr,
     stream: TcpStream,
     config: &ServerConfig,
+    implicit_tls: bool,
 ) -> Result<()> {
-    let mut stream = Stream::Tcp(stream);
+    let mut stream = Stream::new(stream);
+
+    if implicit_tls {
+        let Some(acceptor) = tls::acceptor() else {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "No certificate for implicit TLS",
+            ));
+        };
+        stream = timeout(COMMAND_TIMEOUT, stream.start_tls_server(acceptor))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "TLS handshake timed out"))??;
+    }
 
     stream
         .send_response(Response::new(220, 2, 2, 0, "SMTP mailing-list"))
@@ -26,163 +144,613 @@ pub async fn handle_client(
 
     info!("Greeted");
 
-    let host = init_connection(&mut stream).await?;
-    info!("Got connection from {host}.");
+    let mut session = Session::new();
 
-    let mut request = stream.recieve_request().await?;
+    loop {
+        let request = timeout(COMMAND_TIMEOUT, stream.recieve_request())
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "Client timed out"))??;
 
-    let mut starttls = false;
-    match &request {
-        &Request::StartTls => starttls = true,
-        _ => {}
-    };
+        if !session.accepts(&request) {
+            stream.bad_sequence().await?;
+            continue;
+        }
 
-    if starttls {
-        stream
-            .send_response(Response::new(220, 2, 2, 0, "Go ahead"))
-            .await?;
-        stream = stream.start_tls_server().await?;
+        match request {
+            Request::Ehlo { host } => {
+                greet(&mut stream, config, host, true).await?;
+                session.greet();
+            }
+            Request::Helo { host } => {
+                greet(&mut stream, config, host, false).await?;
+                session.greet();
+            }
+            Request::StartTls => {
+                if stream.is_tls() {
+                    stream.bad_sequence().await?;
+                    continue;
+                }
+                let Some(acceptor) = tls::acceptor() else {
+                    stream
+                        .send_response(Response::new(454, 4, 7, 0, "TLS not available"))
+                        .await?;
+                    continue;
+                };
 
-        init_connection(&mut stream).await?;
-        request = stream.recieve_request().await?;
-    }
+                stream
+                    .send_response(Response::new(220, 2, 2, 0, "Go ahead"))
+                    .await?;
+                stream = timeout(COMMAND_TIMEOUT, stream.start_tls_server(acceptor))
+                    .await
+                    .map_err(|_| Error::new(ErrorKind::TimedOut, "TLS handshake timed out"))??;
+                // RFC 3207: the client has to greet us again after the handshake
+                session = Session::new();
+            }
+            Request::Auth {
+                mechanism,
+                initial_response,
+            } => {
+                // RFC 4954 §4: only once per session
+                if session.authenticated.is_some() {
+                    stream.bad_sequence().await?;
+                    continue;
+                }
+                // Passwords are never sent in the clear
+                if !stream.is_tls() {
+                    stream
+                        .send_response(Response::new(
+                            538,
+                            5,
+                            7,
+                            11,
+                            "Encryption required for requested authentication mechanism",
+                        ))
+                        .await?;
+                    continue;
+                }
+
+                let (username, password) =
+                    match sasl(&mut stream, mechanism, initial_response).await? {
+                        Ok(v) => v,
+                        Err(response) => {
+                            stream.send_response(response).await?;
+                            continue;
+                        }
+                    };
+
+                if !auth::verify(config, &username, &password).await {
+                    info!("Authentication failed for {username}");
+                    stream
+                        .send_response(Response::new(
+                            535,
+                            5,
+                            7,
+                            8,
+                            "Authentication credentials invalid",
+                        ))
+                        .await?;
+                    continue;
+                }
+
+                info!("Authenticated as {username}");
+                stream
+                    .send_response(Response::new(235, 2, 7, 0, "Authentication successful"))
+                    .await?;
+                session.authenticated = Some(username);
+            }
+            Request::Mail { from } => {
+                let address = from.address;
+                info!("Sender: {address}");
+
+                if from.size > config.max_message_size() {
+                    stream.message_too_large().await?;
+                    continue;
+                }
+
+                // BINARYMIME needs BDAT, which we don't offer
+                if from.flags & MAIL_BODY_BINARYMIME != 0 {
+                    stream
+                        .send_response(Response::new(
+                            504,
+                            5,
+                            5,
+                            4,
+                            "BODY=BINARYMIME is not supported",
+                        ))
+                        .await?;
+                    continue;
+                }
+
+                stream
+                    .send_response(Response::new(
+                        250,
+                        2,
+                        1,
+                        0,
+                        format!("Originator {address} okay"),
+                    ))
+                    .await?;
+
+                session.sender = address;
+                session.declared_size = from.size;
+                session.body = if from.flags & MAIL_BODY_8BITMIME != 0 {
+                    Body::EightBitMime
+                } else {
+                    Body::SevenBit
+                };
+                session.smtputf8 = from.flags & MAIL_SMTPUTF8 != 0;
+                session.state = State::InTransaction;
+            }
+            Request::Rcpt { to } => {
+                // Lists are known by their configured address from here on
+                let address = match config.list(&to.address) {
+                    Some((v, _)) => v.to_string(),
+                    None => to.address,
+                };
+                info!("Reciever: {address}");
+
+                // RFC 1870 §6.1 allows per-recipient limits to be enforced here
+                if session.declared_size > config.max_message_size_for(&address) {
+                    stream.message_too_large().await?;
+                    continue;
+                }
+
+                let relay_allowed = session.may_relay(config, addr.ip());
+                if let Some(response) = check_recipient(config, &address, relay_allowed) {
+                    info!("Refusing {address}");
+                    stream.send_response(response).await?;
+                    continue;
+                }
+
+                if let Some(list) = config.lists.get(&address) {
+                    let authenticated = session.authenticated.as_deref();
+                    let response = match posting::decide(list, &session.sender, authenticated).await
+                    {
+                        Ok(Decision::Reject) => Some(Response::new(
+                            550,
+                            5,
+                            7,
+                            1,
+                            format!("You may not post to {address}"),
+                        )),
+                        Ok(_) => None,
+                        Err(_e) => Some(Response::new(
+                            451,
+                            4,
+                            3,
+                            0,
+                            format!("Couldn't check who may post to {address}"),
+                        )),
+                    };
+                    if let Some(response) = response {
+                        info!("Refusing post from {} to {address}", session.sender);
+                        stream.send_response(response).await?;
+                        continue;
+                    }
+                }
 
-    loop {
-        sleep(Duration::from_millis(10)).await;
-        let mail = recieve_mail(&mut stream, request.clone()).await?;
-        match mail.handle(config).await {
-            Ok(_) => {
                 stream
                     .send_response(Response::new(
                         250,
                         2,
-                        6,
+                        1,
                         0,
-                        "Message Recieved Succesfully!!",
+                        format!("Reciever {address} okay"),
                     ))
-                    .await?
+                    .await?;
+
+                session.recipients.push(address);
             }
-            Err(_) => {
+            Request::Data => {
+                session.state = State::InData;
                 stream
-                    .send_response(Response::new(552, 5, 5, 0, "Woopsie"))
-                    .await?
+                    .send_response(Response::new(354, 2, 0, 0, "End with CRLF.CRLF"))
+                    .await?;
+
+                let max_size = session.max_message_size(config);
+                let data = timeout(DATA_TIMEOUT, stream.recieve_mail(max_size))
+                    .await
+                    .map_err(|_| Error::new(ErrorKind::TimedOut, "Client timed out"))??;
+                let data = match data {
+                    Some(v) => v,
+                    None => {
+                        session.reset();
+                        stream.message_too_large().await?;
+                        continue;
+                    }
+                };
+                let mail = session.take_mail(data);
+
+                match mail.handle(config).await {
+                    Ok(_) => {
+                        stream
+                            .send_response(Response::new(
+                                250,
+                                2,
+                                6,
+                                0,
+                                "Message Recieved Succesfully!!",
+                            ))
+                            .await?
+                    }
+                    Err(_) => {
+                        stream
+                            .send_response(Response::new(
+                                451,
+                                4,
+                                3,
+                                0,
+                                "Unable to queue message, try again later",
+                            ))
+                            .await?
+                    }
+                };
             }
-        };
+            Request::Rset => {
+                session.reset();
+                stream
+                    .send_response(Response::new(250, 2, 0, 0, "OK"))
+                    .await?;
+            }
+            Request::Noop { .. } => {
+                stream
+                    .send_response(Response::new(250, 2, 0, 0, "OK"))
+                    .await?;
+            }
+            Request::Help { .. } => {
+                stream
+                    .send_response(Response::new(
+                        214,
+                        2,
+                        0,
+                        0,
+                        "Commands: EHLO HELO STARTTLS AUTH MAIL RCPT DATA RSET NOOP VRFY HELP QUIT",
+                    ))
+                    .await?;
+            }
+            Request::Vrfy { .. } => {
+                stream
+                    .send_response(Response::new(
+                        252,
+                        2,
+                        5,
+                        2,
+                        "Cannot VRFY user, but will accept message",
+                    ))
+                    .await?;
+            }
+            _ => stream.not_implemented().await?,
+        }
     }
 }
 
-async fn recieve_mail(stream: &mut Stream, to: Request<String>) -> Result<Mail> {
-    let sender = get_sender(stream, to).await?;
-    let recipients = get_recipients(stream).await?;
+/// The reply refusing `address`, unless it is a list, the forwarding
+/// server's or `relay_allowed` and on someone else's domain.
+fn check_recipient(
+    config: &ServerConfig,
+    address: &str,
+    relay_allowed: bool,
+) -> Option<Response<String>> {
+    if config.list(address).is_some()
+        || bounce::parse_verp(config, address).is_some()
+        || moderation::parse_address(config, address).is_some()
+        || config.forwards(address)
+    {
+        return None;
+    }
 
-    let data = String::from_utf8_lossy(&stream.recieve_mail().await?).to_string();
+    match domain_of(address) {
+        Some(domain) if !config.is_local_domain(domain) => match relay_allowed {
+            true => None,
+            false => Some(Response::new(550, 5, 7, 1, "Relaying denied".to_string())),
+        },
+        _ => Some(Response::new(
+            550,
+            5,
+            1,
+            1,
+            format!("No such user {address}"),
+        )),
+    }
+}
 
-    let mail = Mail {
-        sender,
-        recipients,
-        data,
-    };
+fn capabilities(config: &ServerConfig, tls: bool) -> Vec<String> {
+    let mut capabilities = vec![
+        "Helu!".to_string(),
+        format!("SIZE {}", config.max_message_size()),
+    ];
 
-    Ok(mail)
-}
+    // Only offered when there is a certificate and we aren't already secure
+    if !tls && tls::acceptor().is_some() {
+        capabilities.push("STARTTLS".to_string());
+    }
 
-async fn get_recipients(stream: &mut Stream) -> Result<Vec<String>> {
-    let mut recipients: Vec<String> = Vec::new();
-    loop {
-        sleep(Duration::from_millis(10)).await;
-        info!("Getting reciever");
-
-        let request = stream.recieve_request().await?;
-        let to = match request {
-            Request::Rcpt { to } => to,
-            Request::Data => break,
-            _ => {
-                stream.protocol_error().await?;
-                continue;
-            }
-        };
+    // Only over TLS, and only if anyone can log in at all
+    if tls && !config.users().is_empty() {
+        capabilities.push("AUTH PLAIN LOGIN".to_string());
+    }
 
-        let address = to.address;
+    capabilities.extend([
+        "PIPELINING".to_string(),
+        "8BITMIME".to_string(),
+        "SMTPUTF8".to_string(),
+        "ENHANCEDSTATUSCODES".to_string(),
+    ]);
 
-        stream
-            .send_response(Response::new(
-                250,
-                2,
-                1,
-                0,
-                format!("Reciever {address} okay"),
-            ))
-            .await?;
+    capabilities
+}
 
-        recipients.push(address)
+/// Sends a SASL challenge and decodes the answer, or gives the reply to send
+/// if the client cancelled or answered with garbage.
+async fn challenge(
+    stream: &mut Stream,
+    challenge: &str,
+) -> Result<std::result::Result<String, Response<String>>> {
+    // No enhanced status code here, the text is all the client should see
+    let challenge = format!("334 {}\r\n", STANDARD.encode(challenge));
+    stream.send_raw(challenge.as_bytes()).await?;
+    let line = stream.recieve_line().await?;
+
+    Ok(decode(&line))
+}
+
+fn decode(response: &str) -> std::result::Result<String, Response<String>> {
+    if response == "*" {
+        return Err(Response::new(
+            501,
+            5,
+            0,
+            0,
+            "Authentication cancelled".to_string(),
+        ));
     }
-    stream
-        .send_response(Response::new(354, 2, 0, 0, "End with CRLF.CRLF"))
-        .await?;
-    return Ok(recipients);
+    // RFC 4954 §4: `=` is an empty initial response
+    if response == "=" {
+        return Ok(String::new());
+    }
+
+    STANDARD
+        .decode(response)
+        .ok()
+        .and_then(|x| String::from_utf8(x).ok())
+        .ok_or_else(|| Response::new(501, 5, 5, 2, "Invalid base64".to_string()))
 }
 
-async fn get_sender(stream: &mut Stream, mut request: Request<String>) -> Result<String> {
-    let mut is_first = true;
-    loop {
-        sleep(Duration::from_millis(10)).await;
-        info!("Getting Sender");
-        if !is_first {
-            request = stream.recieve_request().await?;
-        } else {
-            is_first = false;
-        }
-        let from = match request {
-            Request::Mail { from } => from,
-            _ => {
-                stream.protocol_error().await?;
-                continue;
+/// Runs the PLAIN (RFC 4616) or LOGIN exchange, returning the username and
+/// password the client gave.
+async fn sasl(
+    stream: &mut Stream,
+    mechanism: u64,
+    initial_response: String,
+) -> Result<std::result::Result<(String, String), Response<String>>> {
+    match mechanism {
+        AUTH_PLAIN => {
+            let response = match initial_response.is_empty() {
+                true => challenge(stream, "").await?,
+                false => decode(&initial_response),
+            };
+            let response = match response {
+                Ok(v) => v,
+                Err(e) => return Ok(Err(e)),
+            };
+
+            let mut parts = response.split('\0');
+            let (Some(authzid), Some(authcid), Some(password), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Ok(Err(Response::new(
+                    501,
+                    5,
+                    5,
+                    2,
+                    "Invalid PLAIN response".to_string(),
+                )));
+            };
+            // Acting on behalf of someone else isn't supported
+            if !authzid.is_empty() && authzid != authcid {
+                return Ok(Err(Response::new(
+                    535,
+                    5,
+                    7,
+                    8,
+                    "Authentication credentials invalid".to_string(),
+                )));
             }
-        };
 
-        let address = from.address;
+            Ok(Ok((authcid.to_string(), password.to_string())))
+        }
+        AUTH_LOGIN => {
+            let username = match initial_response.is_empty() {
+                true => challenge(stream, "Username:").await?,
+                false => decode(&initial_response),
+            };
+            let username = match username {
+                Ok(v) => v,
+                Err(e) => return Ok(Err(e)),
+            };
+            let password = match challenge(stream, "Password:").await? {
+                Ok(v) => v,
+                Err(e) => return Ok(Err(e)),
+            };
 
+            Ok(Ok((username, password)))
+        }
+        _ => Ok(Err(Response::new(
+            504,
+            5,
+            5,
+            4,
+            "Unrecognized authentication type".to_string(),
+        ))),
+    }
+}
+
+async fn greet(
+    stream: &mut Stream,
+    config: &ServerConfig,
+    host: String,
+    esmtp: bool,
+) -> Result<()> {
+    info!("Host: {host}");
+    info!("ESMTP: {esmtp}");
+
+    if esmtp {
+        let capabilities = capabilities(config, stream.is_tls());
+        stream.send_capabilities(&capabilities).await?;
+    } else {
         stream
-            .send_response(Response::new(
-                250,
-                2,
-                1,
-                0,
-                format!("Originator {address} okay"),
-            ))
+            .send_response(Response::new(250, 2, 5, 0, format!("Welcome {host}")))
             .await?;
-
-        return Ok(address);
     }
+
+    info!("Finished introduction");
+
+    Ok(())
 }
 
-async fn init_connection(stream: &mut Stream) -> Result<String> {
-    loop {
-        sleep(Duration::from_millis(10)).await;
-        let request = stream.recieve_request().await?;
-
-        let (host, esmtp) = match request {
-            Request::Ehlo { host } => (host, true),
-            Request::Helo { host } => (host, false),
-            _request => {
-                stream.protocol_error().await?;
-                continue;
-            }
-        };
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
 
-        info!("Host: {host}");
-        info!("ESMTP: {esmtp}");
+    type Outcome = std::result::Result<(String, String), Response<String>>;
 
-        if esmtp {
-            (*stream.deref()).write_all(CAPABILITIES).await?;
-        } else {
-            stream
-                .send_response(Response::new(250, 2, 5, 0, format!("Welcome {host}")))
-                .await?;
+    /// Runs a SASL exchange with a client sending `lines`, returning the
+    /// outcome and everything the server sent.
+    async fn exchange(mechanism: u64, initial_response: &str, lines: &[&str]) -> (Outcome, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(address), listener.accept());
+        let mut client = client.unwrap();
+        let mut server = Stream::new(server.unwrap().0);
+
+        for line in lines {
+            client
+                .write_all(format!("{line}\r\n").as_bytes())
+                .await
+                .unwrap();
         }
+        let outcome = sasl(&mut server, mechanism, initial_response.to_string())
+            .await
+            .unwrap();
+        drop(server);
 
-        info!("Finished introduction");
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        (outcome, sent)
+    }
+
+    fn credentials(username: &str, password: &str) -> Outcome {
+        Ok((username.to_string(), password.to_string()))
+    }
+
+    fn code(outcome: Outcome) -> u16 {
+        outcome.unwrap_err().code
+    }
+
+    #[test]
+    fn decodes_responses() {
+        assert_eq!(decode("YWxpY2U="), Ok("alice".to_string()));
+        assert_eq!(decode("="), Ok(String::new()));
+        assert_eq!(decode("*").unwrap_err().code, 501);
+        assert_eq!(decode("not base64!").unwrap_err().code, 501);
+        // Valid base64, but not UTF-8
+        assert_eq!(decode("/w==").unwrap_err().code, 501);
+    }
+
+    #[tokio::test]
+    async fn parses_plain() {
+        let plain = |x: &str| STANDARD.encode(x);
+
+        let (outcome, sent) = exchange(AUTH_PLAIN, &plain("\0alice\0secret"), &[]).await;
+        assert_eq!(outcome, credentials("alice", "secret"));
+        assert_eq!(sent, "");
+
+        let outcome = exchange(AUTH_PLAIN, &plain("alice\0alice\0secret"), &[]).await;
+        assert_eq!(outcome.0, credentials("alice", "secret"));
+        let outcome = exchange(AUTH_PLAIN, &plain("bob\0alice\0secret"), &[]).await;
+        assert_eq!(code(outcome.0), 535);
+        let outcome = exchange(AUTH_PLAIN, &plain("alice\0secret"), &[]).await;
+        assert_eq!(code(outcome.0), 501);
+        let outcome = exchange(AUTH_PLAIN, &plain("\0alice\0secret\0more"), &[]).await;
+        assert_eq!(code(outcome.0), 501);
+        let outcome = exchange(AUTH_PLAIN, "=", &[]).await;
+        assert_eq!(code(outcome.0), 501);
+
+        // Without an initial response the client is asked for it
+        let (outcome, sent) = exchange(AUTH_PLAIN, "", &[&plain("\0alice\0secret")]).await;
+        assert_eq!(outcome, credentials("alice", "secret"));
+        assert_eq!(sent, "334 \r\n");
+        let (outcome, _) = exchange(AUTH_PLAIN, "", &["*"]).await;
+        assert_eq!(code(outcome), 501);
+    }
+
+    #[tokio::test]
+    async fn parses_login() {
+        let (outcome, sent) = exchange(AUTH_LOGIN, "", &["YWxpY2U=", "c2VjcmV0"]).await;
+        assert_eq!(outcome, credentials("alice", "secret"));
+        assert_eq!(sent, "334 VXNlcm5hbWU6\r\n334 UGFzc3dvcmQ6\r\n");
+
+        let (outcome, sent) = exchange(AUTH_LOGIN, "YWxpY2U=", &["c2VjcmV0"]).await;
+        assert_eq!(outcome, credentials("alice", "secret"));
+        assert_eq!(sent, "334 UGFzc3dvcmQ6\r\n");
+
+        let (outcome, sent) = exchange(AUTH_LOGIN, "", &["*"]).await;
+        assert_eq!(code(outcome), 501);
+        assert_eq!(sent, "334 VXNlcm5hbWU6\r\n");
+        let (outcome, _) = exchange(AUTH_LOGIN, "YWxpY2U=", &["%%%"]).await;
+        assert_eq!(code(outcome), 501);
+    }
+
+    #[test]
+    fn decides_who_may_relay() {
+        let config: ServerConfig = toml::from_str(
+            "hostname = \"example.com\"\nplugins = []\n\
+             local_domains = [\"example.net\"]\n\
+             trusted_networks = [\"192.0.2.0/24\", \"2001:db8::/32\"]\n\
+             [lists.\"board@example.com\".Local]\nmembers = []\n",
+        )
+        .unwrap();
+        let trusted: IpAddr = "192.0.2.10".parse().unwrap();
+        let mapped: IpAddr = "::ffff:192.0.2.10".parse().unwrap();
+        let untrusted: IpAddr = "198.51.100.1".parse().unwrap();
+        let mut session = Session::new();
+
+        assert!(session.may_relay(&config, trusted));
+        assert!(session.may_relay(&config, mapped));
+        assert!(session.may_relay(&config, "2001:db8::1".parse().unwrap()));
+        assert!(!session.may_relay(&config, untrusted));
+        session.authenticated = Some("alice@example.com".to_string());
+        assert!(session.may_relay(&config, untrusted));
+
+        let code = |address: &str, relay_allowed: bool| {
+            check_recipient(&config, address, relay_allowed).map(|x| (x.code, x.esc))
+        };
+        for relay_allowed in [true, false] {
+            // Lists are open to everyone, other local addresses to no one
+            assert_eq!(code("board@example.com", relay_allowed), None);
+            assert_eq!(code("board@EXAMPLE.com", relay_allowed), None);
+            assert_eq!(
+                code("nobody@example.com", relay_allowed),
+                Some((550, [5, 1, 1]))
+            );
+            assert_eq!(
+                code("nobody@EXAMPLE.net", relay_allowed),
+                Some((550, [5, 1, 1]))
+            );
+        }
+        assert_eq!(code("friend@example.org", true), None);
+        assert_eq!(code("friend@example.org", false), Some((550, [5, 7, 1])));
+    }
 
-        return Ok(host);
+    #[tokio::test]
+    async fn refuses_other_mechanisms() {
+        let (outcome, sent) = exchange(smtp_proto::AUTH_CRAM_MD5, "", &[]).await;
+        assert_eq!(code(outcome), 504);
+        assert_eq!(sent, "");
     }
 }
@@ -1,6 +1,29 @@
 use color_eyre::eyre::Result;
 use serde::Deserialize;
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::mta_sts;
+
+/// The limit advertised in EHLO when `max_message_size` isn't set (14 MiB).
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 14680064;
+
+pub const DEFAULT_SPOOL: &str = "/var/spool/mailing-list";
+pub const DEFAULT_RETRY_INTERVAL: u64 = 5 * 60;
+pub const DEFAULT_MAX_RETRY_INTERVAL: u64 = 4 * 60 * 60;
+pub const DEFAULT_MAX_LIFETIME: u64 = 5 * 24 * 60 * 60;
+pub const DEFAULT_HELD_LIFETIME: u64 = 14 * 24 * 60 * 60;
+pub const DEFAULT_BOUNCE_THRESHOLD: u32 = 5;
+pub const DEFAULT_BOUNCE_RESET_AFTER: u64 = 7;
+pub const DEFAULT_CERTIFICATE: &str = "cert.pem";
+pub const DEFAULT_KEY: &str = "privkey.pem";
+pub const DEFAULT_MAX_CONNECTIONS: usize = 20;
+pub const DEFAULT_MAX_CONNECTIONS_PER_DOMAIN: usize = 4;
+pub const DEFAULT_MAX_CONNECTIONS_PER_LIST: usize = 10;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct ServerConfig {
@@ -10,6 +33,291 @@ pub struct ServerConfig {
     pub forwarding: Option<ForwardingOptions>,
     pub plugins: Vec<String>,
     pub hostname: String,
+    pub max_message_size: Option<usize>,
+    pub queue: Option<QueueOptions>,
+    pub delivery: Option<DeliveryOptions>,
+    pub tls: Option<TlsOptions>,
+    pub listeners: Option<Vec<ListenerOptions>>,
+    pub outbound_tls: Option<OutboundTlsOptions>,
+    /// Users allowed to log in with SMTP AUTH, and their argon2 password hashes.
+    pub users: Option<HashMap<String, String>>,
+    /// Domains we are the final destination for besides those of the lists.
+    /// Mail to anyone else on them is refused.
+    pub local_domains: Option<Vec<String>>,
+    /// Networks, like `192.0.2.0/24`, whose clients may relay without
+    /// logging in.
+    pub trusted_networks: Option<Vec<String>>,
+    /// Where MTA-STS policies come from, [`mta_sts::Internet`] unless
+    /// something else is put here.
+    #[serde(skip)]
+    pub mta_sts_fetcher: Option<Arc<dyn mta_sts::Fetcher>>,
+}
+
+impl ServerConfig {
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    pub fn queue(&self) -> QueueOptions {
+        self.queue.clone().unwrap_or_default()
+    }
+
+    pub fn delivery(&self) -> DeliveryOptions {
+        self.delivery.unwrap_or_default()
+    }
+
+    pub fn tls(&self) -> TlsOptions {
+        self.tls.clone().unwrap_or_default()
+    }
+
+    pub fn outbound_tls(&self) -> OutboundTlsOptions {
+        self.outbound_tls.clone().unwrap_or_default()
+    }
+
+    pub fn mta_sts_fetcher(&self) -> Arc<dyn mta_sts::Fetcher> {
+        match &self.mta_sts_fetcher {
+            Some(v) => v.clone(),
+            None => Arc::new(mta_sts::Internet),
+        }
+    }
+
+    pub fn users(&self) -> HashMap<String, String> {
+        self.users.clone().unwrap_or_default()
+    }
+
+    /// The sockets to listen on. Without any `[[listeners]]`, `ip` and `port`
+    /// make up a single plaintext one.
+    pub fn listeners(&self) -> Vec<ListenerOptions> {
+        match &self.listeners {
+            Some(v) => v.clone(),
+            None => vec![ListenerOptions {
+                ip: self.ip.clone(),
+                port: self.port.unwrap_or(25),
+                tls: None,
+            }],
+        }
+    }
+
+    /// Whether mail for `address` goes to the forwarding server, i.e. its
+    /// domain is exactly `server_tls`.
+    pub fn forwards(&self, address: &str) -> bool {
+        match (&self.forwarding, domain_of(address)) {
+            (Some(forwarding), Some(domain)) => {
+                forwarding.enable && domain.eq_ignore_ascii_case(&forwarding.server_tls)
+            }
+            _ => false,
+        }
+    }
+
+    /// The list at `address` and the address it is configured under. Case is
+    /// ignored, as nobody expects `Board@Example.com` to be another list.
+    pub fn list(&self, address: &str) -> Option<(&str, &List)> {
+        self.lists
+            .get_key_value(address)
+            .or_else(|| {
+                self.lists
+                    .iter()
+                    .find(|(x, _)| x.eq_ignore_ascii_case(address))
+            })
+            .map(|(x, list)| (x.as_str(), list))
+    }
+
+    /// Whether we are the final destination for `domain`, so only its lists
+    /// exist there.
+    pub fn is_local_domain(&self, domain: &str) -> bool {
+        self.lists
+            .keys()
+            .filter_map(|x| domain_of(x))
+            .chain(self.local_domains.iter().flatten().map(String::as_str))
+            .any(|x| x.eq_ignore_ascii_case(domain))
+    }
+
+    /// Whether clients connecting from `ip` may relay without logging in.
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+
+        self.trusted_networks
+            .iter()
+            .flatten()
+            .any(|x| network_contains(x, ip))
+    }
+
+    /// The size limit for a single recipient, taking per-list overrides into
+    /// account. A list can never raise the limit above the server's own.
+    pub fn max_message_size_for(&self, recipient: &str) -> usize {
+        let max_message_size = self.max_message_size();
+
+        match self.lists.get(recipient).and_then(|x| x.max_message_size) {
+            Some(v) => v.min(max_message_size),
+            None => max_message_size,
+        }
+    }
+}
+
+/// How accepted mail is spooled and retried. Times are in seconds.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct QueueOptions {
+    pub spool: Option<String>,
+    pub retry_interval: Option<u64>,
+    pub max_retry_interval: Option<u64>,
+    pub max_lifetime: Option<u64>,
+    /// How long posts held for moderation wait for a decision.
+    pub held_lifetime: Option<u64>,
+}
+
+impl QueueOptions {
+    pub fn spool(&self) -> &Path {
+        Path::new(self.spool.as_deref().unwrap_or(DEFAULT_SPOOL))
+    }
+
+    /// Exponential backoff, doubling from `retry_interval` up to `max_retry_interval`.
+    pub fn retry_delay(&self, attempts: u32) -> u64 {
+        let retry_interval = self.retry_interval.unwrap_or(DEFAULT_RETRY_INTERVAL);
+        let max_retry_interval = self
+            .max_retry_interval
+            .unwrap_or(DEFAULT_MAX_RETRY_INTERVAL);
+
+        retry_interval
+            .saturating_mul(1 << attempts.saturating_sub(1).min(32))
+            .min(max_retry_interval)
+    }
+
+    pub fn max_lifetime(&self) -> u64 {
+        self.max_lifetime.unwrap_or(DEFAULT_MAX_LIFETIME)
+    }
+
+    pub fn held_lifetime(&self) -> u64 {
+        self.held_lifetime.unwrap_or(DEFAULT_HELD_LIFETIME)
+    }
+}
+
+/// TLS for deliveries to other servers, `domains` overriding `policy` for
+/// single recipient domains.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct OutboundTlsOptions {
+    pub policy: Option<TlsPolicy>,
+    pub domains: Option<HashMap<String, TlsPolicy>>,
+    /// Whether MTA-STS policies (RFC 8461) of recipient domains are honoured.
+    pub mta_sts: Option<bool>,
+    /// Whether mail servers are authenticated by DANE (RFC 7672) when their
+    /// TLSA records are signed.
+    pub dane: Option<bool>,
+}
+
+impl OutboundTlsOptions {
+    pub fn mta_sts(&self) -> bool {
+        self.mta_sts.unwrap_or(true)
+    }
+
+    pub fn dane(&self) -> bool {
+        self.dane.unwrap_or(true)
+    }
+
+    pub fn policy_for(&self, domain: &str) -> TlsPolicy {
+        self.domains
+            .as_ref()
+            .and_then(|x| x.get(&domain.to_ascii_lowercase()))
+            .or(self.policy.as_ref())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// How much TLS a delivery needs before the message is handed over.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TlsPolicy {
+    /// Never use TLS.
+    None,
+    /// Use STARTTLS when it is offered, without checking the certificate.
+    #[default]
+    Opportunistic,
+    /// Fail unless STARTTLS works, without checking the certificate.
+    Required,
+    /// Fail unless STARTTLS works with a valid certificate for the server.
+    Verify,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ListenerOptions {
+    pub ip: Option<String>,
+    pub port: u16,
+    pub tls: Option<ListenerTls>,
+}
+
+impl ListenerOptions {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.ip.as_deref().unwrap_or("0.0.0.0"), self.port)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListenerTls {
+    /// Plaintext, upgraded with STARTTLS if the client wants to.
+    #[default]
+    StartTls,
+    /// TLS from the first byte (RFC 8314), e.g. on port 465.
+    Implicit,
+}
+
+/// Certificates for STARTTLS and implicit TLS. Paths are PEM files, the certificate file
+/// holding the whole chain.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsOptions {
+    pub certificate: Option<String>,
+    pub key: Option<String>,
+    /// Certificates for other names, picked by the name the client asks for.
+    pub sni: Option<Vec<SniCertificate>>,
+}
+
+impl TlsOptions {
+    pub fn certificate(&self) -> PathBuf {
+        PathBuf::from(self.certificate.as_deref().unwrap_or(DEFAULT_CERTIFICATE))
+    }
+
+    pub fn key(&self) -> PathBuf {
+        PathBuf::from(self.key.as_deref().unwrap_or(DEFAULT_KEY))
+    }
+
+    pub fn sni(&self) -> &[SniCertificate] {
+        self.sni.as_deref().unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SniCertificate {
+    /// Host names, `*.example.com` matches one label below `example.com`.
+    pub names: Vec<String>,
+    pub certificate: String,
+    pub key: String,
+}
+
+/// How many outgoing connections deliveries may have open at once. Domains
+/// that share a mail server count as one domain.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeliveryOptions {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_domain: Option<usize>,
+    pub max_connections_per_list: Option<usize>,
+}
+
+impl DeliveryOptions {
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS)
+            .max(1)
+    }
+
+    pub fn max_connections_per_domain(&self) -> usize {
+        self.max_connections_per_domain
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_DOMAIN)
+            .max(1)
+    }
+
+    pub fn max_connections_per_list(&self) -> usize {
+        self.max_connections_per_list
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_LIST)
+            .max(1)
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -18,10 +326,165 @@ pub struct ForwardingOptions {
     pub server: Option<String>,
     pub server_tls: String,
     pub port: Option<u16>,
+    pub tls: Option<TlsPolicy>,
+    /// PEM file with the CAs to trust for the server instead of the usual ones.
+    pub ca_bundle: Option<String>,
+    /// SHA-256 fingerprints of certificates the server may present, in hex.
+    /// A matching certificate is trusted no matter who signed it.
+    pub pinned_certificates: Option<Vec<String>>,
+    /// Logs in to the server with `password`, or with an OAuth 2.0 token
+    /// for XOAUTH2.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub oauth_token: Option<String>,
+    /// Read before every connection, so whatever refreshes the token can
+    /// just replace the file.
+    pub oauth_token_file: Option<String>,
+    /// The SASL mechanism to use, picked from what the server offers if
+    /// left out.
+    pub auth: Option<AuthMechanism>,
+    /// Sends list mail through the server too, instead of straight to the
+    /// members' mail servers.
+    pub relay_all: Option<bool>,
+}
+
+impl ForwardingOptions {
+    /// Certificates are checked unless a weaker policy is asked for.
+    pub fn tls(&self) -> TlsPolicy {
+        self.tls.unwrap_or(TlsPolicy::Verify)
+    }
+
+    pub fn relay_all(&self) -> bool {
+        self.relay_all.unwrap_or(false)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMechanism {
+    Plain,
+    Login,
+    XOAuth2,
 }
 
 #[derive(Deserialize, Debug, Clone)]
-pub enum List {
+pub struct List {
+    #[serde(flatten)]
+    pub members: ListMembers,
+    pub max_message_size: Option<usize>,
+    pub owner: Option<String>,
+    pub dsn: Option<DsnPolicy>,
+    pub bounces: Option<BounceOptions>,
+    pub posting: Option<PostingPolicy>,
+    /// Senders who may always post, and the only ones for `Allowlist`.
+    pub allowed_senders: Option<Vec<String>>,
+    /// What happens to posts the policy doesn't allow.
+    pub refused_posts: Option<PostingAction>,
+    /// Only count the address a client logged in as with SMTP AUTH, not the
+    /// envelope sender, which anyone can make up.
+    pub authenticated_only: Option<bool>,
+    /// Who decides on held posts, the owner if left out. They may always
+    /// post themselves.
+    pub moderators: Option<Vec<String>>,
+    /// Describes the list in its List-Id, e.g. "Styrelsen".
+    pub name: Option<String>,
+    /// The List-Id (RFC 2919), `board.example.com` for `board@example.com`
+    /// if left out.
+    pub list_id: Option<String>,
+    /// Where to read about, join, leave or look back at the list (RFC 2369),
+    /// as URLs or plain addresses.
+    pub help: Option<String>,
+    pub subscribe: Option<String>,
+    pub unsubscribe: Option<String>,
+    pub archive: Option<String>,
+    /// Put in front of every subject, e.g. "[styrelsen]".
+    pub subject_prefix: Option<String>,
+    pub reply_to: Option<ReplyTo>,
+    /// Text added to the end of every post.
+    pub footer: Option<String>,
+}
+
+/// Who may post to a list.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PostingPolicy {
+    /// Anyone.
+    #[default]
+    Open,
+    /// Members and the allowed senders.
+    MembersOnly,
+    /// Only the allowed senders.
+    Allowlist,
+    /// Everyone but the allowed senders is held for moderation.
+    Moderated,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PostingAction {
+    /// Refused during RCPT, so the sender's server tells them.
+    #[default]
+    Reject,
+    /// Accepted and dropped without a word.
+    Discard,
+    /// Kept until a moderator has a look.
+    Hold,
+}
+
+/// Where replies to a post should go.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub enum ReplyTo {
+    /// Whoever the poster asked for, the poster themselves if they didn't.
+    #[default]
+    Poster,
+    /// The whole list.
+    List,
+    /// Somewhere else entirely.
+    Address(String),
+}
+
+/// Automatic bounce processing. Every day a member bounces adds one to their
+/// score, and reaching `threshold` triggers `action`. The score starts over
+/// after `reset_after` days without bounces.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct BounceOptions {
+    pub verp: Option<bool>,
+    pub threshold: Option<u32>,
+    pub action: Option<BounceAction>,
+    pub reset_after: Option<u64>,
+}
+
+impl BounceOptions {
+    pub fn threshold(&self) -> u32 {
+        self.threshold.unwrap_or(DEFAULT_BOUNCE_THRESHOLD)
+    }
+
+    pub fn reset_after(&self) -> u64 {
+        self.reset_after.unwrap_or(DEFAULT_BOUNCE_RESET_AFTER)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BounceAction {
+    /// Stop sending to the member but keep them on the list.
+    #[default]
+    Disable,
+    /// Remove the member from the list file. Lists kept directly in
+    /// `daemon.toml` are only disabled, that file is never rewritten.
+    Unsubscribe,
+}
+
+/// Who is told when a member address fails permanently.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DsnPolicy {
+    /// The list owner. Lists without an owner only log the failure, as
+    /// telling the poster would leak the member addresses.
+    #[default]
+    Owner,
+    /// The envelope sender of the post.
+    Sender,
+    Discard,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub enum ListMembers {
     Local(LocalList),
     Remote(RemoteList),
 }
@@ -49,6 +512,46 @@ struct Medlem {
 }
 
 impl List {
+    pub async fn get_members(&self) -> Result<Vec<String>> {
+        self.members.get_members().await
+    }
+
+    pub fn posting(&self) -> PostingPolicy {
+        self.posting.unwrap_or_default()
+    }
+
+    pub fn allowed_senders(&self) -> &[String] {
+        self.allowed_senders.as_deref().unwrap_or_default()
+    }
+
+    pub fn refused_posts(&self) -> PostingAction {
+        self.refused_posts.unwrap_or_default()
+    }
+
+    pub fn authenticated_only(&self) -> bool {
+        self.authenticated_only.unwrap_or(false)
+    }
+
+    pub fn moderators(&self) -> Vec<String> {
+        match &self.moderators {
+            Some(v) => v.clone(),
+            None => self.owner.iter().cloned().collect(),
+        }
+    }
+
+    pub fn reply_to(&self) -> ReplyTo {
+        self.reply_to.clone().unwrap_or_default()
+    }
+
+    /// Whether deliveries should use a VERP envelope sender.
+    pub fn verp(&self) -> bool {
+        self.bounces
+            .as_ref()
+            .is_some_and(|x| x.verp.unwrap_or(true))
+    }
+}
+
+impl ListMembers {
     pub async fn get_members(&self) -> Result<Vec<String>> {
         Ok(match self.clone() {
             Self::Local(list) => list.members,
@@ -63,10 +566,177 @@ impl List {
             }
         })
     }
+
+    /// Removes `member` from the list file, returning whether anything was
+    /// removed. Local lists can't be changed.
+    pub async fn remove_member(&self, member: &str) -> Result<bool> {
+        let Self::Remote(list) = self else {
+            return Ok(false);
+        };
+
+        // Edited in place, so the comments and layout of the file stay
+        let mut lista: toml_edit::DocumentMut =
+            tokio::fs::read_to_string(&list.location).await?.parse()?;
+        let is_member = |x: Option<&str>| x == Some(member);
+
+        let removed = match lista.get_mut("medlemmar") {
+            Some(toml_edit::Item::ArrayOfTables(medlemmar)) => {
+                let before = medlemmar.len();
+                medlemmar.retain(|x| !is_member(x.get("mail").and_then(|x| x.as_str())));
+                medlemmar.len() != before
+            }
+            Some(toml_edit::Item::Value(toml_edit::Value::Array(medlemmar))) => {
+                let before = medlemmar.len();
+                medlemmar.retain(|x| {
+                    !is_member(
+                        x.as_inline_table()
+                            .and_then(|x| x.get("mail"))
+                            .and_then(|x| x.as_str()),
+                    )
+                });
+                medlemmar.len() != before
+            }
+            _ => false,
+        };
+
+        if removed {
+            crate::queue::write_atomic(Path::new(&list.location), lista.to_string().as_bytes())
+                .await?;
+        }
+
+        Ok(removed)
+    }
+}
+
+/// The domain of an address, with or without angle brackets.
+pub fn domain_of(address: &str) -> Option<&str> {
+    let address = address.trim_start_matches('<').trim_end_matches('>');
+
+    address.rsplit_once('@').map(|(_, domain)| domain)
+}
+
+/// Whether `ip` is in `network`, given as an address with an optional
+/// prefix length.
+fn network_contains(network: &str, ip: IpAddr) -> bool {
+    let (address, prefix) = network.split_once('/').unwrap_or((network, ""));
+    let Ok(address) = address.trim().parse::<IpAddr>() else {
+        return false;
+    };
+    let bits = match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    let prefix = match prefix.trim() {
+        "" => bits,
+        v => match v.parse::<u32>() {
+            Ok(v) if v <= bits => v,
+            _ => return false,
+        },
+    };
+
+    match (address, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
 }
 
 pub fn get_config(file: Option<&str>) -> Result<ServerConfig> {
     let file = &Path::new(file.unwrap_or("/etc/mailing-list/daemon.toml"));
     let file_contents = String::from_utf8(std::fs::read(file)?)?;
-    return Ok(toml::from_str(&file_contents)?);
+    Ok(toml::from_str(&file_contents)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_networks() {
+        assert!(network_contains("192.0.2.0/24", ip("192.0.2.255")));
+        assert!(!network_contains("192.0.2.0/24", ip("192.0.3.0")));
+        assert!(network_contains("192.0.2.128/25", ip("192.0.2.200")));
+        assert!(!network_contains("192.0.2.128/25", ip("192.0.2.127")));
+        assert!(network_contains("192.0.2.1/32", ip("192.0.2.1")));
+        assert!(!network_contains("192.0.2.1/32", ip("192.0.2.2")));
+        assert!(network_contains("192.0.2.1", ip("192.0.2.1")));
+        assert!(!network_contains("192.0.2.1", ip("192.0.2.2")));
+        assert!(network_contains("0.0.0.0/0", ip("203.0.113.7")));
+        assert!(network_contains(" 192.0.2.0 / 24 ", ip("192.0.2.1")));
+
+        assert!(network_contains("2001:db8::/32", ip("2001:db8:ffff::1")));
+        assert!(!network_contains("2001:db8::/32", ip("2001:db9::1")));
+        assert!(network_contains("::1/128", ip("::1")));
+        assert!(!network_contains("::1/128", ip("::2")));
+        assert!(network_contains("::/0", ip("2001:db8::1")));
+
+        // Families never match each other, not even through /0
+        assert!(!network_contains("0.0.0.0/0", ip("2001:db8::1")));
+        assert!(!network_contains("::/0", ip("192.0.2.1")));
+
+        for network in [
+            "192.0.2.0/33",
+            "::/129",
+            "192.0.2.0/",
+            "192.0.2.0/x",
+            "192.0.2/24",
+            "",
+            "example.com",
+        ] {
+            assert!(!network_contains(network, ip("192.0.2.1")), "{network}");
+        }
+    }
+
+    #[test]
+    fn trusts_mapped_addresses() {
+        let config: ServerConfig = toml::from_str(
+            "hostname = \"localhost\"\nplugins = []\n\
+             trusted_networks = [\"127.0.0.0/8\", \"bogus\", \"2001:db8::1\"]\n[lists]\n",
+        )
+        .unwrap();
+
+        assert!(config.is_trusted(ip("127.0.0.1")));
+        assert!(config.is_trusted(ip("::ffff:127.0.0.1")));
+        assert!(config.is_trusted(ip("2001:db8::1")));
+        assert!(!config.is_trusted(ip("::1")));
+        assert!(!config.is_trusted(ip("::ffff:10.0.0.1")));
+    }
+
+    #[tokio::test]
+    async fn removes_members_in_place() {
+        let location = std::env::temp_dir().join(format!("{}.toml", crate::queue::new_id()));
+        let contents = "# Styrelsen\n\n[[medlemmar]]\nnamn = \"Foo\" # ordförande\n\
+                        mail = \"foo@example.com\"\n\n\
+                        [[medlemmar]]\nnamn = \"Bar\"\nmail = \"bar@example.com\"\n";
+        tokio::fs::write(&location, contents).await.unwrap();
+        let list = ListMembers::Remote(RemoteList {
+            location: location.display().to_string(),
+        });
+
+        assert!(!list.remove_member("baz@example.com").await.unwrap());
+        assert!(list.remove_member("bar@example.com").await.unwrap());
+
+        let contents = tokio::fs::read_to_string(&location).await.unwrap();
+        assert_eq!(
+            contents,
+            "# Styrelsen\n\n[[medlemmar]]\nnamn = \"Foo\" # ordförande\n\
+             mail = \"foo@example.com\"\n"
+        );
+        assert_eq!(
+            list.get_members().await.unwrap(),
+            vec!["<foo@example.com>".to_string()]
+        );
+
+        tokio::fs::remove_file(location).await.unwrap();
+    }
 }
@@ -0,0 +1,562 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use domain::{
+    base::{iana::Rcode, Message, MessageBuilder, Name, Rtype, UnknownRecordData},
+    resolv::stub::conf::ResolvConf,
+};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+use tracing::debug;
+
+use crate::{config::TlsPolicy, tls::Requirements};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Large enough for most TLSA sets, small enough to avoid fragmentation.
+const UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// Certificate usages from RFC 6698 §2.1.1. Only the DANE ones are usable for
+/// SMTP (RFC 7672 §3.1).
+const DANE_TA: u8 = 2;
+const DANE_EE: u8 = 3;
+
+/// A TLSA record (RFC 6698).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlsa {
+    pub usage: u8,
+    pub selector: u8,
+    pub matching: u8,
+    pub data: Vec<u8>,
+}
+
+impl Tlsa {
+    fn parse(rdata: &[u8]) -> Option<Self> {
+        let [usage, selector, matching, data @ ..] = rdata else {
+            return None;
+        };
+
+        Some(Self {
+            usage: *usage,
+            selector: *selector,
+            matching: *matching,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Whether we understand the record well enough to authenticate with it.
+    fn is_usable(&self) -> bool {
+        matches!(self.usage, DANE_TA | DANE_EE)
+            && matches!(self.selector, 0 | 1)
+            && matches!(self.matching, 0..=2)
+    }
+
+    fn matches(&self, certificate: &[u8]) -> bool {
+        let selected = match self.selector {
+            0 => certificate,
+            _ => match subject_public_key_info(certificate) {
+                Some(v) => v,
+                None => return false,
+            },
+        };
+
+        match self.matching {
+            0 => selected == self.data,
+            1 => Sha256::digest(selected).as_slice() == self.data,
+            2 => Sha512::digest(selected).as_slice() == self.data,
+            _ => false,
+        }
+    }
+}
+
+/// Reads the tag and length of the DER element at the start of `der`,
+/// returning the header and content lengths.
+fn der_element(der: &[u8]) -> Option<(usize, usize)> {
+    let first = *der.get(1)?;
+    if first < 0x80 {
+        return Some((2, first as usize));
+    }
+
+    let octets = (first & 0x7f) as usize;
+    if octets == 0 || octets > 4 {
+        return None;
+    }
+    let length = der
+        .get(2..2 + octets)?
+        .iter()
+        .fold(0, |length, x| length << 8 | *x as usize);
+
+    Some((2 + octets, length))
+}
+
+/// The DER SubjectPublicKeyInfo of a certificate (RFC 5280 §4.1).
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    // Certificate ::= SEQUENCE { tbsCertificate SEQUENCE { ... } ... }
+    let (header, _) = der_element(certificate)?;
+    let tbs = certificate.get(header..)?;
+    let (header, length) = der_element(tbs)?;
+    let mut rest = tbs.get(header..header + length)?;
+
+    // The version is optional and explicitly tagged
+    if rest.first() == Some(&0xa0) {
+        let (header, length) = der_element(rest)?;
+        rest = rest.get(header + length..)?;
+    }
+
+    // Skip the serial number, signature, issuer, validity and subject
+    for _ in 0..5 {
+        let (header, length) = der_element(rest)?;
+        rest = rest.get(header + length..)?;
+    }
+
+    let (header, length) = der_element(rest)?;
+    rest.get(..header + length)
+}
+
+/// Sends `query` to `server`, over TCP if the answer didn't fit in UDP.
+async fn exchange(server: SocketAddr, query: &Message<Vec<u8>>) -> Result<Message<Vec<u8>>> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(query.as_slice()).await?;
+
+    let mut buffer = vec![0; UDP_PAYLOAD_SIZE as usize];
+    let answer = loop {
+        let length = socket.recv(&mut buffer).await?;
+        match Message::from_octets(buffer[..length].to_vec()) {
+            Ok(v) if v.is_answer(query) => break v,
+            // Not for us, keep waiting
+            _ => continue,
+        }
+    };
+    if !answer.header().tc() {
+        return Ok(answer);
+    }
+
+    let mut stream = TcpStream::connect(server).await?;
+    stream
+        .write_all(&(query.as_slice().len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(query.as_slice()).await?;
+
+    let length = stream.read_u16().await?;
+    let mut buffer = vec![0; length as usize];
+    stream.read_exact(&mut buffer).await?;
+
+    match Message::from_octets(buffer) {
+        Ok(v) if v.is_answer(query) => Ok(v),
+        _ => Err(Error::new(ErrorKind::InvalidData, "Invalid DNS answer")),
+    }
+}
+
+/// Asks the resolvers in /etc/resolv.conf about `name` with the AD bit set,
+/// so the answer says whether they validated it with DNSSEC.
+async fn query(name: &str, rtype: Rtype) -> Result<Message<Vec<u8>>> {
+    let name =
+        Name::vec_from_str(name).map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_random_id();
+    builder.header_mut().set_rd(true);
+    // Asks for the AD bit in the answer (RFC 6840 §5.7), which the stub
+    // resolver never does
+    builder.header_mut().set_ad(true);
+    let mut builder = builder.question();
+    builder
+        .push((&name, rtype))
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    let mut builder = builder.additional();
+    builder
+        .opt(|opt| {
+            opt.set_udp_payload_size(UDP_PAYLOAD_SIZE);
+            Ok(())
+        })
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    let query = builder.into_message();
+
+    let mut servers: Vec<SocketAddr> = Vec::new();
+    for server in ResolvConf::default().servers {
+        if !servers.contains(&server.addr) {
+            servers.push(server.addr);
+        }
+    }
+
+    let mut error = Error::new(ErrorKind::NotFound, "No DNS servers configured");
+    for server in servers {
+        let answer = match timeout(QUERY_TIMEOUT, exchange(server, &query)).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => {
+                error = e;
+                continue;
+            }
+            Err(_) => {
+                error = Error::new(ErrorKind::TimedOut, format!("{server} timed out"));
+                continue;
+            }
+        };
+
+        match answer.header().rcode() {
+            Rcode::NOERROR | Rcode::NXDOMAIN => return Ok(answer),
+            rcode => error = Error::other(format!("{rtype} lookup failed: {rcode}")),
+        }
+    }
+
+    Err(error)
+}
+
+/// The TLSA records for a mail server, if they exist and our resolver
+/// validated them with DNSSEC. Anything unsigned is as good as nothing.
+pub async fn tlsa(host: &str, port: u16) -> Result<Option<Vec<Tlsa>>> {
+    let name = format!("_{port}._tcp.{}", host.trim_end_matches('.'));
+    let answer = query(&name, Rtype::TLSA).await?;
+
+    if !answer.header().ad() {
+        return Ok(None);
+    }
+
+    let records = answer
+        .answer()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?
+        .limit_to::<UnknownRecordData<_>>()
+        .filter_map(|x| x.ok())
+        .filter(|x| x.rtype() == Rtype::TLSA)
+        .filter_map(|x| Tlsa::parse(x.data().data()))
+        .collect();
+
+    Ok(Some(records))
+}
+
+/// Whether the addresses of `host` are signed with DNSSEC, in which case its
+/// TLSA records have to be found before delivering to it.
+async fn is_signed(host: &str) -> Result<bool> {
+    let answer = query(host.trim_end_matches('.'), Rtype::A).await?;
+
+    Ok(answer.header().ad())
+}
+
+/// Tightens `requirements` for a mail server publishing TLSA records
+/// (RFC 7672). Without validated records they are left as they are, but when
+/// the lookup fails for a signed host the server is skipped for now rather
+/// than delivered to without DANE (RFC 7672 §2.2).
+pub async fn secure(requirements: &Requirements, host: &str, port: u16) -> Result<Requirements> {
+    let records = match tlsa(host, port).await {
+        Ok(v) => v,
+        Err(e) => match is_signed(host).await {
+            Ok(false) => {
+                debug!("Couldn't look up TLSA records for unsigned {host}, not using DANE");
+                debug!("Error: {e}");
+                None
+            }
+            Ok(true) => return Err(Error::other(format!("TLSA lookup for {host} failed: {e}"))),
+            Err(_) => {
+                return Err(Error::other(format!(
+                    "TLSA lookup for {host} failed and its addresses couldn't be checked: {e}"
+                )))
+            }
+        },
+    };
+
+    Ok(tighten(requirements, host, records.unwrap_or_default()))
+}
+
+fn tighten(requirements: &Requirements, host: &str, records: Vec<Tlsa>) -> Requirements {
+    let mut requirements = requirements.clone();
+    if records.is_empty() {
+        return requirements;
+    }
+
+    let usable: Vec<Tlsa> = records.into_iter().filter(Tlsa::is_usable).collect();
+    if usable.is_empty() {
+        // Still a promise of TLS, just nothing to authenticate with
+        debug!("{host} has no usable TLSA records, requiring TLS anyway");
+        if matches!(
+            requirements.policy,
+            TlsPolicy::None | TlsPolicy::Opportunistic
+        ) {
+            requirements.policy = TlsPolicy::Required;
+        }
+        return requirements;
+    }
+
+    debug!("Authenticating {host} with {} TLSA records", usable.len());
+    requirements.tlsa = usable;
+    requirements
+}
+
+/// Checks the server against its TLSA records: DANE-EE records have to match
+/// the certificate itself, DANE-TA ones a certificate in the chain which then
+/// has to vouch for the server's name.
+#[derive(Debug)]
+pub struct Verifier {
+    pub provider: Arc<CryptoProvider>,
+    pub records: Vec<Tlsa>,
+}
+
+impl Verifier {
+    fn verify_trust_anchor(
+        &self,
+        anchor: &CertificateDer<'_>,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let mut roots = RootCertStore::empty();
+        roots.add(anchor.clone().into_owned())?;
+
+        WebPkiServerVerifier::builder_with_provider(Arc::new(roots), self.provider.clone())
+            .build()
+            .map_err(|e| rustls::Error::General(e.to_string()))?
+            .verify_server_cert(end_entity, intermediates, server_name, &[], now)
+    }
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        // RFC 7672 §3.1.1: neither names nor expiry matter for DANE-EE
+        let end_entity_matches = self
+            .records
+            .iter()
+            .any(|x| x.usage == DANE_EE && x.matches(end_entity));
+        if end_entity_matches {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let mut error =
+            rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure);
+        for record in self.records.iter().filter(|x| x.usage == DANE_TA) {
+            for anchor in intermediates.iter().filter(|x| record.matches(x)) {
+                match self.verify_trust_anchor(anchor, end_entity, intermediates, server_name, now)
+                {
+                    Ok(v) => return Ok(v),
+                    Err(e) => error = e,
+                }
+            }
+        }
+
+        Err(error)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls_pki_types::pem::PemObject;
+
+    use super::*;
+
+    /// SHA-256 of the SubjectPublicKeyInfo of testdata/localhost.pem, from
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform DER`.
+    const SPKI_SHA256: &str = "8372ace07cac1e9c099351747e925f3f05fc2d5be79f1fbcb69dae8b4704fd31";
+
+    fn certificate(pem: &[u8]) -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(pem).unwrap()
+    }
+
+    fn end_entity() -> CertificateDer<'static> {
+        certificate(include_bytes!("../testdata/localhost.pem"))
+    }
+
+    fn authority() -> CertificateDer<'static> {
+        certificate(include_bytes!("../testdata/ca.pem"))
+    }
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|x| format!("{x:02x}")).collect()
+    }
+
+    fn record(usage: u8, selector: u8, matching: u8, data: &[u8]) -> Tlsa {
+        Tlsa {
+            usage,
+            selector,
+            matching,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn reads_der_lengths() {
+        assert_eq!(der_element(&[0x30, 0x03]), Some((2, 3)));
+        assert_eq!(der_element(&[0x30, 0x81, 0x80]), Some((3, 128)));
+        assert_eq!(der_element(&[0x30, 0x82, 0x01, 0xb0]), Some((4, 432)));
+        // Indefinite and absurd lengths aren't DER
+        assert_eq!(der_element(&[0x30, 0x80]), None);
+        assert_eq!(der_element(&[0x30, 0x85, 1, 1, 1, 1, 1]), None);
+        assert_eq!(der_element(&[0x30, 0x82, 0x01]), None);
+        assert_eq!(der_element(&[0x30]), None);
+    }
+
+    #[test]
+    fn extracts_public_keys() {
+        let certificate = end_entity();
+        let spki = subject_public_key_info(&certificate).unwrap();
+        assert_eq!(hex(&Sha256::digest(spki)), SPKI_SHA256);
+
+        // The certificate is 4 + 4 + 341 bytes up to the end of the
+        // tbsCertificate, which has to be there in full
+        for length in 0..349 {
+            assert_eq!(subject_public_key_info(&certificate[..length]), None);
+        }
+        assert_eq!(subject_public_key_info(&certificate[..349]), Some(spki));
+        assert_eq!(
+            subject_public_key_info(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]),
+            None
+        );
+    }
+
+    #[test]
+    fn matches_selectors_and_matching_types() {
+        let certificate = end_entity();
+        let spki = subject_public_key_info(&certificate).unwrap();
+
+        let matching = [
+            record(DANE_EE, 0, 0, &certificate),
+            record(DANE_EE, 0, 1, &Sha256::digest(&certificate)),
+            record(DANE_EE, 0, 2, &Sha512::digest(&certificate)),
+            record(DANE_EE, 1, 0, spki),
+            record(DANE_EE, 1, 1, &Sha256::digest(spki)),
+            record(DANE_EE, 1, 2, &Sha512::digest(spki)),
+        ];
+        for record in &matching {
+            assert!(record.is_usable());
+            assert!(record.matches(&certificate), "{record:?}");
+        }
+
+        assert!(!record(DANE_EE, 0, 1, &Sha256::digest(spki)).matches(&certificate));
+        assert!(!record(DANE_EE, 1, 1, &Sha256::digest(&certificate)).matches(&certificate));
+        assert!(!record(DANE_EE, 1, 2, &Sha256::digest(spki)).matches(&certificate));
+        assert!(!record(DANE_EE, 1, 1, &Sha256::digest(spki)).matches(&authority()));
+
+        // PKIX usages, private selectors and matching types aren't for us
+        assert!(!record(0, 1, 1, &[]).is_usable());
+        assert!(!record(1, 1, 1, &[]).is_usable());
+        assert!(!record(DANE_TA, 2, 1, &[]).is_usable());
+        assert!(!record(DANE_TA, 1, 255, &[]).is_usable());
+        assert!(!record(DANE_EE, 1, 255, spki).matches(&certificate));
+    }
+
+    fn verify(records: Vec<Tlsa>, intermediates: &[CertificateDer<'_>], name: &str) -> bool {
+        let verifier = Verifier {
+            provider: Arc::new(rustls::crypto::aws_lc_rs::default_provider()),
+            records,
+        };
+        let name = ServerName::try_from(name.to_string()).unwrap();
+
+        verifier
+            .verify_server_cert(&end_entity(), intermediates, &name, &[], UnixTime::now())
+            .is_ok()
+    }
+
+    #[test]
+    fn verifies_end_entities() {
+        let spki = Sha256::digest(subject_public_key_info(&end_entity()).unwrap());
+
+        // Names don't matter for DANE-EE
+        assert!(verify(
+            vec![record(DANE_EE, 1, 1, &spki)],
+            &[],
+            "mx.example.com"
+        ));
+        assert!(!verify(
+            vec![record(DANE_EE, 1, 1, &[0; 32])],
+            &[],
+            "localhost"
+        ));
+        // A DANE-TA record for the server's own certificate isn't enough
+        assert!(!verify(
+            vec![record(DANE_TA, 1, 1, &spki)],
+            &[],
+            "localhost"
+        ));
+    }
+
+    #[test]
+    fn verifies_trust_anchors() {
+        let anchor = Sha256::digest(authority());
+        let records = vec![record(DANE_TA, 0, 1, &anchor)];
+
+        assert!(verify(records.clone(), &[authority()], "localhost"));
+        // The anchor has to vouch for the name, and be in the chain
+        assert!(!verify(records.clone(), &[authority()], "mx.example.com"));
+        assert!(!verify(records, &[], "localhost"));
+        assert!(!verify(
+            vec![record(DANE_EE, 0, 1, &anchor)],
+            &[authority()],
+            "localhost"
+        ));
+    }
+
+    #[test]
+    fn tightens_requirements() {
+        let requirements = Requirements::default();
+
+        assert_eq!(tighten(&requirements, "mx", Vec::new()), requirements);
+
+        let unusable = tighten(&requirements, "mx", vec![record(1, 1, 1, &[0; 32])]);
+        assert_eq!(unusable.policy, TlsPolicy::Required);
+        assert!(unusable.tlsa.is_empty());
+
+        let records = vec![record(1, 1, 1, &[0; 32]), record(DANE_EE, 1, 1, &[0; 32])];
+        let usable = tighten(&requirements, "mx", records);
+        assert_eq!(usable.tlsa, [record(DANE_EE, 1, 1, &[0; 32])]);
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{DsnPolicy, ServerConfig},
+    mail::{Body, Mail},
+    message,
+    queue::{self, Recipient, Status},
+};
+
+/// Reports recipients that will never get a message as RFC 3464 delivery
+/// status notifications, one per person to notify.
+pub async fn notify(config: &ServerConfig, sender: &str, failed: &[Recipient], original: &[u8]) {
+    // Never answer a bounce with another bounce (RFC 5321 §4.5.5)
+    if sender == "<>" {
+        return;
+    }
+
+    let mut reports: HashMap<String, Vec<&Recipient>> = HashMap::new();
+
+    for recipient in failed {
+        match report_to(config, sender, recipient) {
+            Some(to) => reports.entry(to).or_default().push(recipient),
+            None => info!("Not reporting failed delivery to {}", recipient.address),
+        }
+    }
+
+    for (to, recipients) in reports {
+        let data = build(&config.hostname, &to, &recipients, original);
+        let mail = Mail {
+            sender: "<>".to_string(),
+            recipients: Vec::new(),
+            body: if data.is_ascii() {
+                Body::SevenBit
+            } else {
+                Body::EightBitMime
+            },
+            data,
+            smtputf8: false,
+            authenticated: None,
+        };
+        let recipient = Recipient::new(to.clone(), queue::route(config, &to), None);
+
+        match queue::enqueue(config, &mail, vec![recipient]).await {
+            Ok(_) => info!("Reported failed delivery to {to}"),
+            Err(_e) => {
+                warn!("Couldn't queue delivery status notification to {to}");
+                debug!("Error: {_e}");
+            }
+        }
+    }
+}
+
+/// Who to tell about `recipient`, following the policy of the list it came
+/// from. Mail that didn't come through a list goes back to the sender.
+fn report_to(config: &ServerConfig, sender: &str, recipient: &Recipient) -> Option<String> {
+    let sender = sender.trim_start_matches('<').trim_end_matches('>');
+
+    let Some(list) = &recipient.list else {
+        return Some(sender.to_string());
+    };
+    let list = config.lists.get(list)?;
+
+    match list.dsn.unwrap_or_default() {
+        DsnPolicy::Owner => list.owner.clone(),
+        DsnPolicy::Sender => Some(sender.to_string()),
+        DsnPolicy::Discard => None,
+    }
+}
+
+fn build(hostname: &str, to: &str, recipients: &[&Recipient], original: &[u8]) -> Vec<u8> {
+    let id = queue::new_id();
+    let boundary = format!("{id}/{hostname}");
+    let date = date(queue::now());
+
+    let mut human = format!(
+        "This is the mail system at host {hostname}.\r\n\r\n\
+         Your message could not be delivered to one or more recipients.\r\n\r\n"
+    );
+    let mut status = format!("Reporting-MTA: dns; {hostname}\r\n");
+
+    for recipient in recipients {
+        let address = recipient
+            .address
+            .trim_start_matches('<')
+            .trim_end_matches('>');
+        let error = recipient.last_error.clone().unwrap_or_default();
+        let code = match recipient.status {
+            Status::Expired => "4.4.7",
+            _ => status_code(&error).unwrap_or("5.0.0"),
+        };
+
+        human.push_str(&format!("<{address}>: {error}\r\n"));
+        status.push_str(&format!(
+            "\r\nFinal-Recipient: rfc822; {address}\r\nAction: failed\r\nStatus: {code}\r\n"
+        ));
+        if let Some(reply) = diagnostic_code(&error) {
+            status.push_str(&format!("Diagnostic-Code: smtp; {reply}\r\n"));
+        }
+    }
+
+    let mut report = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{hostname}>\r\n\
+         To: <{to}>\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{id}@{hostname}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n\
+         \tboundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         {human}\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         {status}\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/rfc822-headers\r\n\
+         \r\n"
+    )
+    .into_bytes();
+
+    report.extend(message::header_section(original));
+    report.extend(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    report
+}
+
+/// The reply of the remote server in an error message, without what we
+/// wrapped it in, e.g. `550 5.1.1 No such user`. Errors that didn't come
+/// from a server have none.
+fn diagnostic_code(error: &str) -> Option<&str> {
+    let (_, reply) = error.split_once(" rejected: ")?;
+    let code = reply.get(..3)?;
+
+    match code.bytes().all(|x| x.is_ascii_digit()) && reply[3..].starts_with([' ', '-']) {
+        true => Some(reply),
+        false => None,
+    }
+}
+
+/// Picks the enhanced status code, e.g. `5.1.1`, out of an error message.
+pub fn status_code(error: &str) -> Option<&str> {
+    let is_number = |x: &str| (1..=3).contains(&x.len()) && x.bytes().all(|x| x.is_ascii_digit());
+
+    error.split_whitespace().find(|x| {
+        let parts: Vec<&str> = x.split('.').collect();
+        matches!(parts[..], ["4" | "5", subject, detail] if is_number(subject) && is_number(detail))
+    })
+}
+
+/// Formats a unix timestamp as an RFC 5322 date in UTC.
+pub fn date(timestamp: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let days = timestamp / 86400;
+    let seconds = timestamp % 86400;
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{Content, Message},
+        queue::Route,
+    };
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(date(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        assert_eq!(date(1709164800), "Thu, 29 Feb 2024 00:00:00 +0000");
+        assert_eq!(date(1709251199), "Thu, 29 Feb 2024 23:59:59 +0000");
+        assert_eq!(date(1704067199), "Sun, 31 Dec 2023 23:59:59 +0000");
+        assert_eq!(date(1704067200), "Mon, 01 Jan 2024 00:00:00 +0000");
+    }
+
+    #[test]
+    fn finds_status_codes() {
+        let error = "Permanent failure: RCPT rejected: 550 5.1.1 No such user";
+        assert_eq!(status_code(error), Some("5.1.1"));
+        assert_eq!(diagnostic_code(error), Some("550 5.1.1 No such user"));
+
+        let error = "Permanent failure: 5.1.2 Domain example.invalid does not exist";
+        assert_eq!(status_code(error), Some("5.1.2"));
+        assert_eq!(diagnostic_code(error), None);
+
+        assert_eq!(status_code("Transient failure: 1.2.3 or 4.5"), None);
+    }
+
+    #[test]
+    fn builds_reports() {
+        let mut failed = Recipient::new("<foo@example.net>".to_string(), Route::Mx, None);
+        failed.status = Status::Failed;
+        failed.last_error =
+            Some("Permanent failure: RCPT rejected: 550 5.1.1 No such user".to_string());
+        let mut expired = Recipient::new("<bar@example.net>".to_string(), Route::Mx, None);
+        expired.status = Status::Expired;
+
+        let original = b"Subject: Hej\r\nFrom: <baz@example.org>\r\n\r\nSecret body\r\n";
+        let data = build(
+            "example.com",
+            "baz@example.org",
+            &[&failed, &expired],
+            original,
+        );
+        let report = Message::parse(&data);
+
+        assert_eq!(report.header.value("To").unwrap(), "<baz@example.org>");
+        let content_type = report.content_type();
+        assert_eq!(content_type.mime_type, "multipart/report");
+        assert_eq!(
+            content_type.parameter("report-type"),
+            Some("delivery-status")
+        );
+
+        let Content::Multipart(multipart) = &report.body else {
+            panic!("Not a multipart");
+        };
+        let parts = multipart.parts();
+        let types: Vec<String> = parts.iter().map(|x| x.content_type().mime_type).collect();
+        assert_eq!(
+            types,
+            [
+                "text/plain",
+                "message/delivery-status",
+                "text/rfc822-headers"
+            ]
+        );
+
+        let status = String::from_utf8(parts[1].body.to_bytes()).unwrap();
+        assert!(status.starts_with("Reporting-MTA: dns; example.com\r\n"));
+        assert!(status.contains(
+            "Final-Recipient: rfc822; foo@example.net\r\nAction: failed\r\n\
+             Status: 5.1.1\r\nDiagnostic-Code: smtp; 550 5.1.1 No such user\r\n"
+        ));
+        assert!(status.contains(
+            "Final-Recipient: rfc822; bar@example.net\r\nAction: failed\r\nStatus: 4.4.7\r\n"
+        ));
+
+        let headers = String::from_utf8(parts[2].body.to_bytes()).unwrap();
+        assert!(headers.contains("Subject: Hej\r\n"));
+        assert!(!headers.contains("Secret body"));
+    }
+}
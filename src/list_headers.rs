@@ -0,0 +1,156 @@
+use crate::{
+    config::List,
+    message::{self, Field, Message},
+};
+
+/// Fields a list replaces, so members never see another list's.
+const LIST_FIELDS: [&str; 9] = [
+    "List-Id",
+    "List-Post",
+    "List-Help",
+    "List-Subscribe",
+    "List-Unsubscribe",
+    "List-Unsubscribe-Post",
+    "List-Owner",
+    "List-Archive",
+    "Precedence",
+];
+
+/// `board.example.com` for `board@example.com`.
+fn default_list_id(address: &str) -> String {
+    address.replacen('@', ".", 1)
+}
+
+/// A URL for a List-* field, addresses turning into `mailto:` ones.
+fn url(value: &str) -> String {
+    match value.contains(':') {
+        true => format!("<{value}>"),
+        false => format!("<mailto:{value}>"),
+    }
+}
+
+/// The List-* fields (RFC 2369, RFC 2919) describing `list`.
+fn fields(address: &str, list: &List) -> Vec<(&'static str, String)> {
+    let list_id = list
+        .list_id
+        .clone()
+        .unwrap_or_else(|| default_list_id(address));
+
+    let mut fields = vec![(
+        "List-Id",
+        match &list.name {
+            Some(name) => format!("{} <{list_id}>", message::phrase(name)),
+            None => format!("<{list_id}>"),
+        },
+    )];
+
+    let optional = [
+        ("List-Help", &list.help),
+        ("List-Subscribe", &list.subscribe),
+        ("List-Unsubscribe", &list.unsubscribe),
+    ];
+    fields.extend(
+        optional
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, url(value.as_ref()?)))),
+    );
+
+    fields.push(("List-Post", url(address)));
+    if let Some(owner) = &list.owner {
+        fields.push(("List-Owner", url(owner)));
+    }
+    if let Some(archive) = &list.archive {
+        fields.push(("List-Archive", url(archive)));
+    }
+    fields.push(("Precedence", "list".to_string()));
+
+    fields
+}
+
+/// Adds the list's fields at the end of the header section, replacing any
+/// the message had before.
+pub fn add(address: &str, list: &List, message: &mut Message) {
+    for name in LIST_FIELDS {
+        message.header.remove(name);
+    }
+    for (name, value) in fields(address, list) {
+        message.header.push(Field::new(name, &value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(settings: &str) -> List {
+        toml::from_str(&format!("{settings}\n[Local]\nmembers = []\n")).unwrap()
+    }
+
+    fn headers(settings: &str) -> Message {
+        let mut message = Message::parse(
+            b"Subject: Hej\r\nList-Id: <other.example.org>\r\n\
+              List-Unsubscribe: <mailto:leave@example.org>\r\n\r\nbody\r\n",
+        );
+        add("board@example.com", &list(settings), &mut message);
+        message
+    }
+
+    #[test]
+    fn describes_lists() {
+        let message = headers(
+            "name = \"Styrelsen\"\nowner = \"admin@example.com\"\n\
+             help = \"https://example.com/lists/board\"\n\
+             unsubscribe = \"board-leave@example.com\"\n\
+             subscribe = \"mailto:board-join@example.com?subject=join\"",
+        );
+        let value = |name: &str| message.header.value(name);
+
+        assert_eq!(value("List-Id").unwrap(), "Styrelsen <board.example.com>");
+        assert_eq!(value("List-Post").unwrap(), "<mailto:board@example.com>");
+        assert_eq!(
+            value("List-Help").unwrap(),
+            "<https://example.com/lists/board>"
+        );
+        assert_eq!(
+            value("List-Unsubscribe").unwrap(),
+            "<mailto:board-leave@example.com>"
+        );
+        assert_eq!(
+            value("List-Subscribe").unwrap(),
+            "<mailto:board-join@example.com?subject=join>"
+        );
+        assert_eq!(value("List-Owner").unwrap(), "<mailto:admin@example.com>");
+        assert_eq!(value("Precedence").unwrap(), "list");
+        assert_eq!(value("List-Archive"), None);
+        assert_eq!(value("Subject").unwrap(), "Hej");
+
+        // Replaced, not added to
+        let count = |name: &str| message.header.fields.iter().filter(|x| x.is(name)).count();
+        assert_eq!(count("List-Id"), 1);
+        assert_eq!(count("List-Unsubscribe"), 1);
+    }
+
+    #[test]
+    fn names_list_ids() {
+        let list_id = |settings: &str| headers(settings).header.value("List-Id").unwrap();
+
+        assert_eq!(list_id(""), "<board.example.com>");
+        assert_eq!(
+            list_id("list_id = \"styrelsen.udf.se\""),
+            "<styrelsen.udf.se>"
+        );
+        assert_eq!(
+            list_id("name = \"Ung Data Falun, styrelsen\""),
+            "\"Ung Data Falun, styrelsen\" <board.example.com>"
+        );
+        assert_eq!(
+            list_id("name = \"U.D.F. \\\"board\\\"\""),
+            "\"U.D.F. \\\"board\\\"\" <board.example.com>"
+        );
+
+        let message = headers("name = \"Styrelsen för UDF\"");
+        let field = message.header.get("List-Id").unwrap();
+        assert!(field.value().starts_with("=?utf-8?b?"));
+        assert_eq!(field.text(), "Styrelsen för UDF <board.example.com>");
+    }
+}
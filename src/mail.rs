@@ -1,76 +1,178 @@
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
 use crate::{
-    config::ServerConfig,
-    send_mail::{self, send_group},
+    bounce,
+    config::{List, ServerConfig},
+    list_headers,
+    message::{self, Message},
+    moderation,
+    posting::{self, Decision},
+    queue::{self, Recipient, Route},
+    rewrite,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Mail {
     pub sender: String,
     pub recipients: Vec<String>,
-    pub data: String,
+    pub data: Vec<u8>,
+    pub body: Body,
+    pub smtputf8: bool,
+    /// Who the client authenticated as with SMTP AUTH, if anyone.
+    pub authenticated: Option<String>,
+}
+
+/// The `BODY=` parameter the message was submitted with (RFC 6152).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Body {
+    #[default]
+    SevenBit,
+    EightBitMime,
 }
 
 pub enum Error {
-    SendError,
+    ListError,
+    QueueError,
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
 impl Mail {
+    /// Whether the message actually contains any 8-bit data, regardless of
+    /// what the client declared.
+    pub fn is_8bit(&self) -> bool {
+        !self.data.is_ascii()
+    }
+
+    /// Whether delivering the message needs SMTPUTF8 from the next hop (RFC 6531),
+    /// i.e. the envelope or the header section isn't plain ASCII.
+    pub fn requires_smtputf8(&self, to: &str) -> bool {
+        if !self.smtputf8 {
+            return false;
+        }
+
+        !self.sender.is_ascii() || !to.is_ascii() || !message::header_section(&self.data).is_ascii()
+    }
+
+    /// The message parsed into its header and MIME parts.
+    pub fn message(&self) -> Message {
+        Message::parse(&self.data)
+    }
+
+    /// Replaces the message, e.g. with a changed version of [`Mail::message`].
+    pub fn set_message(&mut self, message: &Message) {
+        self.data = message.to_bytes();
+        if self.is_8bit() {
+            self.body = Body::EightBitMime;
+        }
+    }
+
+    /// Expands lists, forwarded and relayed recipients and hands the result
+    /// to the outbound queue. Returning `Ok` means the message is safely on disk.
     pub async fn handle(mut self, config: &ServerConfig) -> Result<()> {
         let lists = &config.lists;
-        let forwarding_enabled = config.forwarding.clone().is_some_and(|x| x.enable);
 
         self.sender = format!("<{}>", self.sender);
 
-        for recipient in self.recipients {
-            if lists.contains_key(&recipient) {
-                info!("Sending to everyone subscribing to {recipient}");
-                send_group(
-                    &config.hostname,
-                    self.data.clone(),
-                    &config
-                        .lists
-                        .get(&recipient)
-                        .unwrap()
-                        .get_members()
-                        .await
-                        .unwrap(),
-                    &self.sender,
-                )
-                .await;
+        let mut deliveries = Vec::new();
+        // Every list gets its own copy, with its own headers
+        let mut posts = Vec::new();
+
+        for recipient in &self.recipients {
+            if let Some((list, member)) = bounce::parse_verp(config, recipient) {
+                if bounce::is_failure(&self.data) {
+                    bounce::record(config, &list, &member).await;
+                } else {
+                    debug!("Ignoring non-failure report for {member} on {list}");
+                }
+                continue;
             }
 
-            if !forwarding_enabled {
+            if let Some((list, token)) = moderation::parse_address(config, recipient) {
+                moderation::reply(config, &list, &token, &self).await;
                 continue;
             }
 
-            let forwarding = config.forwarding.clone().unwrap();
+            if let Some(list) = lists.get(recipient) {
+                let decision = posting::decide(list, &self.sender, self.authenticated.as_deref());
+                match decision.await {
+                    Ok(Decision::Accept) => {}
+                    Ok(Decision::Hold) => {
+                        if let Err(_e) = moderation::hold(config, recipient, &self).await {
+                            warn!("Couldn't hold post to {recipient}");
+                            debug!("Error: {_e}");
+                            return Err(Error::QueueError);
+                        }
+                        continue;
+                    }
+                    // Rejected posts only get here if the policy changed after RCPT
+                    Ok(Decision::Discard | Decision::Reject) => {
+                        info!("Discarding post from {} to {recipient}", self.sender);
+                        continue;
+                    }
+                    Err(_e) => {
+                        warn!("Couldn't get members of {recipient}");
+                        debug!("Error: {_e}");
+                        return Err(Error::ListError);
+                    }
+                }
 
-            if !recipient.ends_with(&forwarding.server_tls) {
-                continue;
+                info!("Sending to everyone subscribing to {recipient}");
+                match list_post(config, recipient, list, &self).await {
+                    Ok(v) => posts.push(v),
+                    Err(_e) => {
+                        warn!("Couldn't get members of {recipient}");
+                        debug!("Error: {_e}");
+                        return Err(Error::ListError);
+                    }
+                }
             }
 
-            let server = forwarding.server.unwrap_or(forwarding.server_tls.clone());
-
-            match send_mail::send(
-                &config.hostname,
-                &self.data,
-                &recipient,
-                &self.sender,
-                server,
-                forwarding.port,
-                forwarding.server_tls,
-            )
-            .await
-            {
-                Ok(_) => (),
-                Err(_) => return Err(Error::SendError),
-            };
+            if config.forwards(recipient) {
+                deliveries.push(Recipient::new(recipient.clone(), Route::Forward, None));
+            } else if !lists.contains_key(recipient) {
+                // Checked at RCPT time, so this is someone we may relay for
+                info!("Relaying to {recipient}");
+                deliveries.push(Recipient::new(recipient.clone(), Route::Mx, None));
+            }
+        }
+
+        posts.push((self.clone(), deliveries));
+
+        for (mail, deliveries) in posts {
+            if let Err(_e) = queue::enqueue(config, &mail, deliveries).await {
+                warn!("Couldn't queue mail from {}", self.sender);
+                debug!("Error: {_e}");
+                return Err(Error::QueueError);
+            }
         }
 
         Ok(())
     }
 }
+
+/// A post to `list` the way its members get it, with the list's headers,
+/// subject prefix, Reply-To and footer, and every active member to send it to.
+pub async fn list_post(
+    config: &ServerConfig,
+    address: &str,
+    list: &List,
+    mail: &Mail,
+) -> color_eyre::eyre::Result<(Mail, Vec<Recipient>)> {
+    let members = list.get_members().await?;
+    let members = bounce::active_members(config, address, members).await;
+    let recipients = members
+        .into_iter()
+        .map(|x| Recipient::new(x, Route::Mx, Some(address.to_string())))
+        .collect();
+
+    let mut message = mail.message();
+    rewrite::apply(config, address, list, &mut message);
+    list_headers::add(address, list, &mut message);
+
+    let mut post = mail.clone();
+    post.set_message(&message);
+
+    Ok((post, recipients))
+}
@@ -3,40 +3,53 @@
 #[macro_use]
 extern crate dlopen_derive;
 
-use std::{fmt::Debug, sync::Mutex, time::Duration};
+use std::{fmt::Debug, sync::Mutex};
 
 use clap::Parser;
-use cli::Cli;
+use cli::{Cli, Command};
 use client_handler::handle_client;
 use color_eyre::eyre::Result;
-use config::get_config;
+use config::{get_config, ListenerTls, ServerConfig};
 use dlopen::wrapper::Container;
 use plugins::PluginApi;
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncBufRead, AsyncWrite, BufStream},
     net::{TcpListener, TcpStream},
     runtime::Runtime,
-    time::timeout,
+    task::JoinSet,
 };
-use tokio_rustls::{client, server, TlsStream};
+use tokio_rustls::TlsStream;
 use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
+mod auth;
+mod bounce;
 mod cli;
 mod client_handler;
 mod config;
+mod dane;
+mod dsn;
+mod list_headers;
 mod mail;
+mod message;
+mod moderation;
+mod mta_sts;
 mod plugins;
+mod posting;
+mod queue;
+mod resolver;
+mod rewrite;
 mod send_mail;
 mod stream;
+mod tls;
 
-trait AsyncStream: AsyncRead + AsyncWrite + std::marker::Unpin + Send + Debug {}
-impl AsyncStream for TcpStream {}
-impl AsyncStream for TlsStream<TcpStream> {}
-impl AsyncStream for client::TlsStream<TcpStream> {}
-impl AsyncStream for server::TlsStream<TcpStream> {}
+trait AsyncStream: AsyncBufRead + AsyncWrite + std::marker::Unpin + Send + Debug {}
+impl AsyncStream for BufStream<TcpStream> {}
+impl AsyncStream for BufStream<TlsStream<TcpStream>> {}
 
-pub static PLUGINS: Mutex<Option<Vec<(mlpa::Plugin, Container<PluginApi>)>>> = Mutex::new(None);
+type LoadedPlugin = (mlpa::Plugin, Container<PluginApi>);
+
+pub static PLUGINS: Mutex<Option<Vec<LoadedPlugin>>> = Mutex::new(None);
 
 fn main() -> Result<()> {
     let runtime = Runtime::new()?;
@@ -46,6 +59,18 @@ fn main() -> Result<()> {
 }
 
 async fn run() -> Result<()> {
+    let args = Cli::parse();
+
+    // Subcommands work on the spool of a running daemon and then exit
+    if let Some(command) = args.command {
+        color_eyre::install()?;
+        let config = get_config(args.config.as_deref())?;
+
+        return match command {
+            Command::Moderate(action) => moderation::run(&config, action).await,
+        };
+    }
+
     let format_stdout = tracing_subscriber::fmt::format()
         .with_line_number(true)
         .with_source_location(false);
@@ -80,15 +105,18 @@ async fn run() -> Result<()> {
 
     color_eyre::install()?;
 
-    let args = Cli::parse();
-
     let config = get_config(args.config.as_deref())?;
-    let port = config.port.unwrap_or(25);
-    let ip = config.ip.clone().unwrap_or("0.0.0.0".to_string());
 
-    info!("Starting mailing-list on port {port}");
-    let listener = TcpListener::bind(format!("{ip}:{port}")).await?;
-    info!("Started mailing-list on port {port}");
+    let mut listeners = Vec::new();
+    for options in config.listeners() {
+        let address = options.address();
+
+        info!("Starting mailing-list on {address}");
+        let listener = TcpListener::bind(&address).await?;
+        info!("Started mailing-list on {address}");
+
+        listeners.push((listener, options.tls.unwrap_or_default()));
+    }
 
     let mut plugins = Vec::new();
 
@@ -111,6 +139,44 @@ async fn run() -> Result<()> {
 
     *PLUGINS.lock().unwrap() = Some(plugins);
 
+    if let Err(_e) = tls::load(&config.tls()) {
+        warn!("Couldn't load TLS certificates, STARTTLS is disabled until they can be");
+        debug!("Error: {_e}");
+    }
+    tokio::spawn(tls::watch(args.config.clone(), config.clone()));
+
+    if tls::acceptor().is_none() && listeners.iter().any(|(_, x)| *x == ListenerTls::Implicit) {
+        warn!("No TLS certificate, connections to implicit TLS listeners will be dropped");
+    }
+
+    tokio::spawn(queue::run(args.config.clone(), config.clone()));
+
+    let mut tasks = JoinSet::new();
+    for (listener, tls) in listeners {
+        tasks.spawn(listen(
+            listener,
+            tls == ListenerTls::Implicit,
+            args.config.clone(),
+            config.clone(),
+        ));
+    }
+
+    while let Some(task) = tasks.join_next().await {
+        task??;
+    }
+
+    Ok(())
+}
+
+/// Accepts connections on one listener forever, starting TLS right away if
+/// `implicit_tls` is set. The configuration is read again for every
+/// connection, keeping the last one that worked.
+async fn listen(
+    listener: TcpListener,
+    implicit_tls: bool,
+    config_file: Option<String>,
+    mut config: ServerConfig,
+) -> Result<()> {
     loop {
         let (stream, addr) = match listener.accept().await {
             Ok(v) => {
@@ -123,12 +189,20 @@ async fn run() -> Result<()> {
             }
         };
 
-        let config = get_config(args.config.as_deref())?;
-        tokio::spawn(timeout(Duration::from_secs(10), async move {
-            match handle_client(addr, stream, &config).await {
+        match get_config(config_file.as_deref()) {
+            Ok(v) => config = v,
+            Err(_e) => {
+                warn!("Couldn't reload configuration, using the old one");
+                debug!("Error: {_e}");
+            }
+        };
+
+        let config = config.clone();
+        tokio::spawn(async move {
+            match handle_client(addr, stream, &config, implicit_tls).await {
                 Ok(_) => {}
                 Err(e) => warn!("Error: {e}"),
             };
-        }));
+        });
     }
 }
@@ -0,0 +1,926 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Lines longer than this are folded when a field is made (RFC 5322 §2.2.3).
+const FOLD_LENGTH: usize = 78;
+/// Encoded lines of base64 and quoted-printable bodies (RFC 2045 §6.7, §6.8).
+const ENCODED_LINE_LENGTH: usize = 76;
+/// How deep parts may be nested before the rest is left as it is.
+const MAX_DEPTH: usize = 32;
+
+/// A header field exactly as it was received, folding and all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    raw: Vec<u8>,
+}
+
+impl Field {
+    /// A new field, folded at spaces if it gets too long.
+    pub fn new(name: &str, value: &str) -> Self {
+        let mut raw = format!("{name}:");
+        let mut line_length = raw.len();
+        for word in value.split(' ') {
+            if line_length + 1 + word.len() > FOLD_LENGTH && line_length > name.len() + 1 {
+                raw.push_str("\r\n");
+                line_length = 0;
+            }
+            raw.push(' ');
+            raw.push_str(word);
+            line_length += 1 + word.len();
+        }
+        raw.push_str("\r\n");
+
+        Self {
+            raw: raw.into_bytes(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        let end = self
+            .raw
+            .iter()
+            .position(|x| *x == b':')
+            .unwrap_or(self.raw.len());
+
+        std::str::from_utf8(&self.raw[..end]).unwrap_or("").trim()
+    }
+
+    pub fn is(&self, name: &str) -> bool {
+        self.name().eq_ignore_ascii_case(name)
+    }
+
+    /// Everything after the colon, folds included but without the final line
+    /// break.
+    pub fn raw_value(&self) -> &[u8] {
+        let start = match self.raw.iter().position(|x| *x == b':') {
+            Some(v) => v + 1,
+            None => self.raw.len(),
+        };
+        let line = self.raw.strip_suffix(b"\n").unwrap_or(&self.raw);
+        let end = line.strip_suffix(b"\r").unwrap_or(line).len();
+
+        &self.raw[start.min(end)..end]
+    }
+
+    /// The value unfolded (RFC 5322 §2.2.3), encoded words left alone.
+    pub fn value(&self) -> String {
+        let value = String::from_utf8_lossy(self.raw_value());
+
+        value
+            .replace("\r\n", "")
+            .replace('\n', "")
+            .trim()
+            .to_string()
+    }
+
+    /// The value unfolded and with encoded words decoded.
+    pub fn text(&self) -> String {
+        decode(&self.value())
+    }
+}
+
+/// The header section of a message or MIME part, in order.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Header {
+    pub fields: Vec<Field>,
+}
+
+impl Header {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut fields: Vec<Field> = Vec::new();
+        for line in data.split_inclusive(|x| *x == b'\n') {
+            match fields.last_mut() {
+                // Folded lines belong to the field before them
+                Some(field) if line.starts_with(b" ") || line.starts_with(b"\t") => {
+                    field.raw.extend(line);
+                }
+                _ => fields.push(Field { raw: line.to_vec() }),
+            }
+        }
+
+        Self { fields }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|x| x.is(name))
+    }
+
+    /// The unfolded value of the first `name` field.
+    pub fn value(&self, name: &str) -> Option<String> {
+        self.get(name).map(Field::value)
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|x| !x.is(name));
+    }
+
+    /// Replaces the first `name` field where it stands, dropping any others,
+    /// or adds it at the end.
+    pub fn set(&mut self, field: Field) {
+        let name = field.name().to_string();
+        match self.fields.iter().position(|x| x.is(&name)) {
+            Some(index) => {
+                self.fields[index] = field;
+                let mut seen = 0;
+                self.fields.retain(|x| {
+                    seen += x.is(&name) as usize;
+                    !x.is(&name) || seen == 1
+                });
+            }
+            None => self.push(field),
+        }
+    }
+
+    pub fn push(&mut self, field: Field) {
+        // Don't glue the new field onto an unterminated last line
+        if let Some(last) = self.fields.last_mut() {
+            if !last.raw.ends_with(b"\n") {
+                last.raw.extend(b"\r\n");
+            }
+        }
+        self.fields.push(field);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.fields.iter().flat_map(|x| x.raw.clone()).collect()
+    }
+}
+
+/// The header section of a message, without the empty line ending it.
+pub fn header_section(data: &[u8]) -> &[u8] {
+    if data.starts_with(b"\r\n") {
+        return &[];
+    }
+
+    match data.windows(4).position(|x| x == b"\r\n\r\n") {
+        Some(end) => &data[..end + 2],
+        None => data,
+    }
+}
+
+/// A message (RFC 5322) or one of its MIME parts (RFC 2045), which turns back
+/// into the very same bytes unless something is changed.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Message {
+    pub header: Header,
+    pub body: Content,
+    /// Whether there was an empty line after the header, even with no body.
+    separated: bool,
+}
+
+/// The body of a message or part.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Content {
+    /// Anything that isn't made of other parts, still transfer encoded.
+    Single(Vec<u8>),
+    Multipart(Multipart),
+    /// A message/rfc822 or message/global part.
+    Message(Box<Message>),
+}
+
+impl Default for Content {
+    fn default() -> Self {
+        Self::Single(Vec::new())
+    }
+}
+
+impl Content {
+    fn parse(header: &Header, body: &[u8], depth: usize) -> Self {
+        if depth >= MAX_DEPTH {
+            return Self::Single(body.to_vec());
+        }
+
+        let content_type = content_type(header);
+        if content_type.mime_type.starts_with("multipart/") {
+            if let Some(v) = content_type
+                .parameter("boundary")
+                .and_then(|x| Multipart::parse(x, body, depth))
+            {
+                return Self::Multipart(v);
+            }
+        }
+
+        // Only unencoded messages can be parsed as they are (RFC 2046 §5.2.1)
+        let nested = matches!(
+            content_type.mime_type.as_str(),
+            "message/rfc822" | "message/global"
+        );
+        let identity = matches!(
+            transfer_encoding(header).as_str(),
+            "7bit" | "8bit" | "binary"
+        );
+        if nested && identity && !body.is_empty() {
+            return Self::Message(Box::new(Message::parse_at(body, depth + 1)));
+        }
+
+        Self::Single(body.to_vec())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Single(v) => v.clone(),
+            Self::Multipart(v) => v.to_bytes(),
+            Self::Message(v) => v.to_bytes(),
+        }
+    }
+}
+
+impl Message {
+    pub fn parse(data: &[u8]) -> Self {
+        Self::parse_at(data, 0)
+    }
+
+    fn parse_at(data: &[u8], depth: usize) -> Self {
+        let header = header_section(data);
+        let (body, separated) = match data.get(header.len()..header.len() + 2) {
+            Some(b"\r\n") => (&data[header.len() + 2..], true),
+            _ => (&data[header.len()..], false),
+        };
+        let header = Header::parse(header);
+        let body = Content::parse(&header, body, depth);
+
+        Self {
+            header,
+            body,
+            separated,
+        }
+    }
+
+    pub fn new(header: Header, body: Content) -> Self {
+        Self {
+            header,
+            body,
+            separated: true,
+        }
+    }
+
+    /// The type of the body, `text/plain` if the message doesn't say
+    /// (RFC 2045 §5.2).
+    pub fn content_type(&self) -> ContentType {
+        content_type(&self.header)
+    }
+
+    /// The Content-Transfer-Encoding in lower case, `7bit` if unset.
+    pub fn transfer_encoding(&self) -> String {
+        transfer_encoding(&self.header)
+    }
+
+    /// The body with its transfer encoding undone, if it is a single part
+    /// in an encoding we know.
+    pub fn decoded(&self) -> Option<Vec<u8>> {
+        let Content::Single(body) = &self.body else {
+            return None;
+        };
+
+        match self.transfer_encoding().as_str() {
+            "7bit" | "8bit" | "binary" => Some(body.clone()),
+            "base64" => decode_base64(body),
+            "quoted-printable" => Some(decode_quoted_printable(body)),
+            _ => None,
+        }
+    }
+
+    /// Replaces the body with `data`, encoded the way the header says.
+    /// Returns `false` for encodings we don't know, leaving the body alone.
+    pub fn set_decoded(&mut self, data: &[u8]) -> bool {
+        let encoded = match self.transfer_encoding().as_str() {
+            "7bit" | "8bit" | "binary" => data.to_vec(),
+            "base64" => encode_base64(data),
+            "quoted-printable" => encode_quoted_printable(data),
+            _ => return false,
+        };
+        self.body = Content::Single(encoded);
+
+        true
+    }
+
+    /// The body of a text part as a string, if its charset is one we know.
+    pub fn text(&self) -> Option<String> {
+        let content_type = self.content_type();
+        if !content_type.mime_type.starts_with("text/") {
+            return None;
+        }
+        let data = self.decoded()?;
+
+        match content_type.parameter("charset") {
+            None => Some(String::from_utf8_lossy(&data).into_owned()),
+            Some(charset) => decode_charset(&charset.to_ascii_lowercase(), &data),
+        }
+    }
+
+    /// This part and every part inside it, depth first.
+    pub fn walk(&self) -> Vec<&Message> {
+        let mut parts = vec![self];
+        match &self.body {
+            Content::Single(_) => {}
+            Content::Multipart(v) => parts.extend(v.parts().iter().flat_map(Message::walk)),
+            Content::Message(v) => parts.extend(v.walk()),
+        }
+
+        parts
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.header.to_bytes();
+        let body = self.body.to_bytes();
+        if self.separated || !body.is_empty() {
+            if !data.is_empty() && !data.ends_with(b"\n") {
+                data.extend(b"\r\n");
+            }
+            data.extend(b"\r\n");
+        }
+        data.extend(body);
+
+        data
+    }
+}
+
+/// The parts of a multipart body (RFC 2046 §5.1), with the delimiters kept as
+/// they were.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Multipart {
+    boundary: String,
+    pub preamble: Vec<u8>,
+    parts: Vec<Message>,
+    /// The delimiter line before each part, with the line break before it.
+    delimiters: Vec<Vec<u8>>,
+    /// The closing delimiter, empty if the body ended without one.
+    close: Vec<u8>,
+    pub epilogue: Vec<u8>,
+}
+
+impl Multipart {
+    pub fn new(boundary: &str) -> Self {
+        Self {
+            boundary: boundary.to_string(),
+            preamble: Vec::new(),
+            parts: Vec::new(),
+            delimiters: Vec::new(),
+            close: format!("\r\n--{boundary}--\r\n").into_bytes(),
+            epilogue: Vec::new(),
+        }
+    }
+
+    fn parse(boundary: &str, body: &[u8], depth: usize) -> Option<Self> {
+        let dash_boundary = format!("--{boundary}");
+
+        // Where each delimiter starts and ends, and whether it's the last
+        let mut delimiters = Vec::new();
+        let mut position = 0;
+        for line in body.split_inclusive(|x| *x == b'\n') {
+            let start = position;
+            position += line.len();
+
+            let Some(rest) = line.strip_prefix(dash_boundary.as_bytes()) else {
+                continue;
+            };
+            let (rest, closing) = match rest.strip_prefix(b"--") {
+                Some(v) => (v, true),
+                None => (rest, false),
+            };
+            // Only transport padding may follow the boundary
+            if !rest.iter().all(|x| b" \t\r\n".contains(x)) {
+                continue;
+            }
+
+            // The line break before a delimiter is part of it
+            let start = match &body[..start] {
+                [.., b'\r', b'\n'] => start - 2,
+                [.., b'\n'] => start - 1,
+                _ => start,
+            };
+            delimiters.push((start, position, closing));
+            if closing {
+                break;
+            }
+        }
+
+        let first = delimiters.first()?;
+        if first.2 {
+            return None;
+        }
+
+        let mut multipart = Self {
+            boundary: boundary.to_string(),
+            preamble: body[..first.0].to_vec(),
+            parts: Vec::new(),
+            delimiters: Vec::new(),
+            close: Vec::new(),
+            epilogue: Vec::new(),
+        };
+        for (i, (start, end, closing)) in delimiters.iter().copied().enumerate() {
+            if closing {
+                multipart.close = body[start..end].to_vec();
+                multipart.epilogue = body[end..].to_vec();
+                break;
+            }
+
+            let next = delimiters.get(i + 1).map_or(body.len(), |x| x.0);
+            multipart.delimiters.push(body[start..end].to_vec());
+            multipart
+                .parts
+                .push(Message::parse_at(&body[end..next], depth + 1));
+        }
+
+        Some(multipart)
+    }
+
+    pub fn parts(&self) -> &[Message] {
+        &self.parts
+    }
+
+    /// Adds a part after the last one.
+    pub fn push(&mut self, part: Message) {
+        let boundary = &self.boundary;
+        let delimiter = match self.parts.is_empty() && self.preamble.is_empty() {
+            true => format!("--{boundary}\r\n"),
+            false => format!("\r\n--{boundary}\r\n"),
+        };
+        if self.close.is_empty() {
+            self.close = format!("\r\n--{boundary}--\r\n").into_bytes();
+        }
+
+        self.delimiters.push(delimiter.into_bytes());
+        self.parts.push(part);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.preamble.clone();
+        for (delimiter, part) in self.delimiters.iter().zip(&self.parts) {
+            data.extend(delimiter);
+            data.extend(part.to_bytes());
+        }
+        data.extend(&self.close);
+        data.extend(&self.epilogue);
+
+        data
+    }
+}
+
+fn content_type(header: &Header) -> ContentType {
+    header
+        .value("Content-Type")
+        .and_then(|x| ContentType::parse(&x))
+        .unwrap_or_default()
+}
+
+fn transfer_encoding(header: &Header) -> String {
+    header
+        .value("Content-Transfer-Encoding")
+        .map(|x| x.to_ascii_lowercase())
+        .unwrap_or_else(|| "7bit".to_string())
+}
+
+/// A parsed Content-Type (RFC 2045 §5.1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    /// `type/subtype` in lower case.
+    pub mime_type: String,
+    /// Parameters with lower case names and unquoted values.
+    pub parameters: Vec<(String, String)>,
+}
+
+impl Default for ContentType {
+    fn default() -> Self {
+        Self {
+            mime_type: "text/plain".to_string(),
+            parameters: vec![("charset".to_string(), "us-ascii".to_string())],
+        }
+    }
+}
+
+impl ContentType {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut rest = value.trim_start();
+        let end = rest.find([';', ' ', '\t', '(']).unwrap_or(rest.len());
+        let mime_type = rest[..end].to_ascii_lowercase();
+        if !mime_type.contains('/') {
+            return None;
+        }
+        rest = &rest[end..];
+
+        let mut parameters = Vec::new();
+        while let Some(start) = rest.find(';') {
+            rest = rest[start + 1..].trim_start();
+            let Some((name, after)) = rest.split_once('=') else {
+                break;
+            };
+            let after = after.trim_start();
+
+            let value = match after.strip_prefix('"') {
+                Some(quoted) => {
+                    let mut value = String::new();
+                    let mut chars = quoted.char_indices();
+                    let mut end = quoted.len();
+                    while let Some((i, c)) = chars.next() {
+                        match c {
+                            '\\' => value.extend(chars.next().map(|(_, x)| x)),
+                            '"' => {
+                                end = i + 1;
+                                break;
+                            }
+                            c => value.push(c),
+                        }
+                    }
+                    rest = &quoted[end..];
+                    value
+                }
+                None => {
+                    let end = after.find([';', ' ', '\t']).unwrap_or(after.len());
+                    rest = &after[end..];
+                    after[..end].to_string()
+                }
+            };
+
+            parameters.push((name.trim().to_ascii_lowercase(), value));
+        }
+
+        Some(Self {
+            mime_type,
+            parameters,
+        })
+    }
+
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, x)| x.as_str())
+    }
+}
+
+/// Base64 in lines of 76 characters (RFC 2045 §6.8).
+pub fn encode_base64(data: &[u8]) -> Vec<u8> {
+    STANDARD
+        .encode(data)
+        .as_bytes()
+        .chunks(ENCODED_LINE_LENGTH)
+        .flat_map(|x| [x, b"\r\n"].concat())
+        .collect()
+}
+
+/// Decodes base64, skipping line breaks and anything else outside the
+/// alphabet as RFC 2045 §6.8 says to.
+pub fn decode_base64(data: &[u8]) -> Option<Vec<u8>> {
+    let data: Vec<u8> = data
+        .iter()
+        .copied()
+        .filter(|x| x.is_ascii_alphanumeric() || matches!(x, b'+' | b'/' | b'='))
+        .collect();
+
+    STANDARD.decode(data).ok()
+}
+
+/// Quoted-printable (RFC 2045 §6.7), keeping the line breaks of `data`.
+pub fn encode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() * 2);
+    let mut lines = data.split(|x| *x == b'\n').peekable();
+
+    while let Some(line) = lines.next() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut length = 0;
+        for (i, x) in line.iter().copied().enumerate() {
+            let last = i + 1 == line.len();
+            let literal = match x {
+                b'=' => false,
+                // Whitespace at the end of a line would be lost
+                b' ' | b'\t' => !last,
+                33..=126 => true,
+                _ => false,
+            };
+            let encoded = match literal {
+                true => vec![x],
+                false => format!("={x:02X}").into_bytes(),
+            };
+
+            // Leaves room for the `=` of a soft line break
+            let room = match last {
+                true => ENCODED_LINE_LENGTH,
+                false => ENCODED_LINE_LENGTH - 1,
+            };
+            if length + encoded.len() > room {
+                output.extend(b"=\r\n");
+                length = 0;
+            }
+            length += encoded.len();
+            output.extend(encoded);
+        }
+
+        if lines.peek().is_some() {
+            output.extend(b"\r\n");
+        }
+    }
+
+    output
+}
+
+pub fn decode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut lines = data.split(|x| *x == b'\n').peekable();
+
+    while let Some(line) = lines.next() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        // Trailing whitespace was added in transport (RFC 2045 §6.7)
+        let line = line.trim_ascii_end();
+        let (line, soft) = match line.strip_suffix(b"=") {
+            Some(v) => (v, true),
+            None => (line, false),
+        };
+
+        let mut i = 0;
+        while i < line.len() {
+            let hex = line
+                .get(i + 1..i + 3)
+                .and_then(|x| std::str::from_utf8(x).ok())
+                .and_then(|x| u8::from_str_radix(x, 16).ok());
+            match (line[i], hex) {
+                (b'=', Some(x)) => {
+                    output.push(x);
+                    i += 3;
+                }
+                // Broken encodings are kept as they are
+                (x, _) => {
+                    output.push(x);
+                    i += 1;
+                }
+            }
+        }
+
+        if !soft && lines.peek().is_some() {
+            output.extend(b"\r\n");
+        }
+    }
+
+    output
+}
+
+/// Text in one of the charsets we know, which is UTF-8 and its subsets plus
+/// Latin-1.
+fn decode_charset(charset: &str, data: &[u8]) -> Option<String> {
+    match charset {
+        "utf-8" | "utf8" | "us-ascii" | "ascii" => Some(String::from_utf8_lossy(data).into_owned()),
+        "iso-8859-1" | "latin1" => Some(data.iter().map(|x| *x as char).collect()),
+        _ => None,
+    }
+}
+
+/// A header phrase as it may be sent, as encoded words (RFC 2047) unless it
+/// is plain ASCII.
+pub fn encode(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+
+    // 45 bytes make 60 characters of base64, keeping each word within the
+    // 75 characters RFC 2047 §2 allows
+    let mut words = Vec::new();
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        if i + c.len_utf8() - start > 45 {
+            words.push(&value[start..i]);
+            start = i;
+        }
+    }
+    words.push(&value[start..]);
+
+    words
+        .iter()
+        .map(|x| format!("=?utf-8?b?{}?=", STANDARD.encode(x)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A display name for an address field (RFC 5322 §3.2.5), quoted when it
+/// has characters an atom can't.
+pub fn phrase(value: &str) -> String {
+    const SPECIALS: &[char] = &[
+        '(', ')', '<', '>', '[', ']', ':', ';', '@', '\\', ',', '.', '"',
+    ];
+
+    if !value.is_ascii() {
+        return encode(value);
+    }
+    // Quoted, `=?` can't be mistaken for the start of an encoded word either
+    if value.contains(SPECIALS)
+        || value.contains("=?")
+        || value.chars().any(|x| x.is_ascii_control())
+        || value.trim() != value
+        || value.is_empty()
+    {
+        let escaped: String = value
+            .chars()
+            .filter(|x| !matches!(x, '\r' | '\n'))
+            .flat_map(|x| match x {
+                '"' | '\\' => vec!['\\', x],
+                _ => vec![x],
+            })
+            .collect();
+        return format!("\"{escaped}\"");
+    }
+
+    value.to_string()
+}
+
+/// Decodes one encoded word, `=?charset?encoding?text?=`.
+fn decode_word(word: &str) -> Option<String> {
+    let inner = word.strip_prefix("=?")?.strip_suffix("?=")?;
+    let mut parts = inner.splitn(3, '?');
+    // A language may follow the charset (RFC 2231 §5)
+    let charset = parts.next()?.split('*').next()?.to_ascii_lowercase();
+    let encoding = parts.next()?;
+    let text = parts.next()?;
+
+    let bytes = match encoding {
+        "b" | "B" => STANDARD.decode(text).ok()?,
+        "q" | "Q" => {
+            let mut bytes = Vec::new();
+            let mut input = text.bytes();
+            while let Some(x) = input.next() {
+                match x {
+                    b'_' => bytes.push(b' '),
+                    b'=' => {
+                        let hex = [input.next()?, input.next()?];
+                        let hex = std::str::from_utf8(&hex).ok()?;
+                        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                    }
+                    x => bytes.push(x),
+                }
+            }
+            bytes
+        }
+        _ => return None,
+    };
+
+    // Unknown charsets are still better shown than not
+    Some(
+        decode_charset(&charset, &bytes)
+            .unwrap_or_else(|| String::from_utf8_lossy(&bytes).into_owned()),
+    )
+}
+
+/// An unfolded header value with its encoded words (RFC 2047) decoded.
+/// Whitespace between two encoded words is dropped, as it should be.
+pub fn decode(value: &str) -> String {
+    let mut output = String::new();
+    let mut pending_space = String::new();
+    let mut last_was_word = false;
+
+    for token in value.split_inclusive([' ', '\t']) {
+        let word = token.trim_end_matches([' ', '\t']);
+        let space = &token[word.len()..];
+        if word.is_empty() {
+            pending_space.push_str(space);
+            continue;
+        }
+
+        match decode_word(word) {
+            Some(decoded) => {
+                if !last_was_word {
+                    output.push_str(&pending_space);
+                }
+                output.push_str(&decoded);
+                last_was_word = true;
+            }
+            None => {
+                output.push_str(&pending_space);
+                output.push_str(word);
+                last_was_word = false;
+            }
+        }
+        pending_space = space.to_string();
+    }
+    output.push_str(&pending_space);
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"From: a@example.com\r\n\
+        Subject: =?utf-8?q?H=C3=A4lsningar?=\r\n =?utf-8?b?IGZyw6Vu?= Falun\r\n\
+        Content-Type: multipart/mixed;\r\n\tboundary=\"outer\"\r\n\
+        \r\n\
+        preamble\r\n\
+        --outer\r\n\
+        Content-Type: multipart/alternative; boundary=inner\r\n\
+        \r\n\
+        --inner  \r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Transfer-Encoding: quoted-printable\r\n\
+        \r\n\
+        H=C3=A4j=\r\n d=C3=A5\r\n\
+        --inner\r\n\
+        Content-Type: text/html\r\n\
+        \r\n\
+        <p>Hej</p>\r\n\
+        --inner--\r\n\
+        \r\n\
+        --outer\r\n\
+        Content-Type: message/rfc822\r\n\
+        \r\n\
+        Subject: inside\r\n\
+        \r\n\
+        text\r\n\
+        --outer--\r\n\
+        epilogue\r\n";
+
+    #[test]
+    fn serializes_losslessly() {
+        assert_eq!(Message::parse(MESSAGE).to_bytes(), MESSAGE);
+
+        for data in [
+            &b""[..],
+            b"\r\nbody",
+            b"Subject: x\r\n",
+            b"Subject: x",
+            b"x\r\n\r\n",
+        ] {
+            assert_eq!(Message::parse(data).to_bytes(), data);
+        }
+    }
+
+    #[test]
+    fn parses_parts() {
+        let message = Message::parse(MESSAGE);
+
+        assert_eq!(
+            message.header.get("Subject").unwrap().text(),
+            "Hälsningar från Falun"
+        );
+        let parts = message.walk();
+        let types: Vec<String> = parts.iter().map(|x| x.content_type().mime_type).collect();
+        assert_eq!(
+            types,
+            [
+                "multipart/mixed",
+                "multipart/alternative",
+                "text/plain",
+                "text/html",
+                "message/rfc822",
+                "text/plain"
+            ]
+        );
+        assert_eq!(parts[2].text().unwrap(), "Häj då");
+        assert_eq!(parts[5].header.value("Subject").unwrap(), "inside");
+    }
+
+    #[test]
+    fn changes_parts() {
+        let mut message = Message::parse(MESSAGE);
+        let Content::Multipart(multipart) = &mut message.body else {
+            panic!("Not a multipart");
+        };
+        multipart.push(Message::new(
+            Header::default(),
+            Content::Single(b"x".to_vec()),
+        ));
+
+        let data = String::from_utf8(message.to_bytes()).unwrap();
+        assert!(data.ends_with("text\r\n--outer\r\n\r\nx\r\n--outer--\r\nepilogue\r\n"));
+    }
+
+    #[test]
+    fn encodes_bodies() {
+        let text = "Hälsningar, en rad som är så lång att den måste brytas för att få plats \
+                    på raden\r\nslut \r\n";
+        let encoded = encode_quoted_printable(text.as_bytes());
+        assert!(encoded.split(|x| *x == b'\n').all(|x| x.len() <= 77));
+        assert_eq!(decode_quoted_printable(&encoded), text.as_bytes());
+
+        let encoded = encode_base64(text.as_bytes());
+        assert_eq!(decode_base64(&encoded).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn encodes_words() {
+        let phrase = "Styrelsen för Ung Data Falun, som har ett väldigt långt namn";
+        let encoded = encode(phrase);
+        assert!(encoded.split(' ').all(|x| x.len() <= 75));
+        assert_eq!(decode(&encoded), phrase);
+
+        // Folded between the words, the first one can't go anywhere else
+        let field = Field::new("Subject", &encoded);
+        let lines: Vec<&[u8]> = field.raw.split(|x| *x == b'\n').collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].len() <= FOLD_LENGTH);
+        assert_eq!(field.text(), phrase);
+    }
+
+    #[test]
+    fn quotes_phrases() {
+        assert_eq!(phrase("Styrelsen"), "Styrelsen");
+        assert_eq!(phrase("Ung Data Falun"), "Ung Data Falun");
+        assert_eq!(
+            phrase("Ung Data Falun, styrelsen"),
+            "\"Ung Data Falun, styrelsen\""
+        );
+        assert_eq!(phrase("board@example.com"), "\"board@example.com\"");
+        assert_eq!(phrase("U.D.F."), "\"U.D.F.\"");
+        assert_eq!(phrase("The \"board\""), "\"The \\\"board\\\"\"");
+        assert_eq!(phrase("back\\slash"), "\"back\\\\slash\"");
+        assert_eq!(phrase("=?utf-8?q?x?="), "\"=?utf-8?q?x?=\"");
+        assert_eq!(phrase(""), "\"\"");
+        assert_eq!(phrase("Styrelsen för UDF"), encode("Styrelsen för UDF"));
+    }
+}
@@ -0,0 +1,524 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::{eyre, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    cli::Moderate,
+    config::ServerConfig,
+    dsn,
+    mail::{self, Body, Mail},
+    message::{self, Field, Header, Message},
+    posting,
+    queue::{self, now, Recipient},
+};
+
+/// A post kept back from a list, stored next to its message. It is known by
+/// a random token, which moderators reply to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Held {
+    pub list: String,
+    pub sender: String,
+    pub body: Body,
+    pub smtputf8: bool,
+    pub authenticated: Option<String>,
+    pub created: u64,
+}
+
+/// What a moderator decided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verdict {
+    Approve,
+    Reject,
+}
+
+fn held_dir(config: &ServerConfig) -> PathBuf {
+    config.queue().spool().join("held")
+}
+
+/// The files of a held post. Tokens end up in paths, so nothing but what
+/// [`new_token`] makes is accepted.
+fn held_files(config: &ServerConfig, token: &str) -> Option<(PathBuf, PathBuf)> {
+    if token.len() != 32
+        || !token
+            .bytes()
+            .all(|x| matches!(x, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return None;
+    }
+    let dir = held_dir(config);
+
+    Some((
+        dir.join(format!("{token}.toml")),
+        dir.join(format!("{token}.eml")),
+    ))
+}
+
+fn new_token() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+/// The address moderators reply to for a held post, e.g.
+/// `board-moderate+0123…@example.com` for `board@example.com`.
+pub fn moderation_address(list: &str, token: &str) -> String {
+    let (local, domain) = list.rsplit_once('@').unwrap_or((list, ""));
+
+    format!("{local}-moderate+{token}@{domain}")
+}
+
+/// Recognises an address made by [`moderation_address`], returning the list
+/// and the token.
+pub fn parse_address(config: &ServerConfig, recipient: &str) -> Option<(String, String)> {
+    let recipient = recipient.trim_start_matches('<').trim_end_matches('>');
+    let (local, domain) = recipient.rsplit_once('@')?;
+    let (list_local, token) = local.split_once("-moderate+")?;
+    let (list, _) = config.list(&format!("{list_local}@{domain}"))?;
+    held_files(config, token)?;
+
+    Some((list.to_string(), token.to_string()))
+}
+
+/// Stores `mail` for `list` until a moderator decides on it and tells the
+/// moderators, returning its token.
+pub async fn hold(config: &ServerConfig, list: &str, mail: &Mail) -> Result<String> {
+    let dir = held_dir(config);
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let token = new_token();
+    let held = Held {
+        list: list.to_string(),
+        sender: mail.sender.clone(),
+        body: mail.body,
+        smtputf8: mail.smtputf8,
+        authenticated: mail.authenticated.clone(),
+        created: now(),
+    };
+    let (description, message) =
+        held_files(config, &token).ok_or_else(|| eyre!("Invalid token {token}"))?;
+
+    // Like the queue, the message first and the description last
+    queue::write_atomic(&message, &mail.data).await?;
+    queue::write_atomic(&description, toml::to_string(&held)?.as_bytes()).await?;
+
+    info!("Holding {token} from {} for {list}", mail.sender);
+    notify(config, &token, &held, &mail.data).await;
+
+    Ok(token)
+}
+
+async fn load(config: &ServerConfig, token: &str) -> Result<(Held, Vec<u8>)> {
+    let (description, message) =
+        held_files(config, token).ok_or_else(|| eyre!("Invalid token {token}"))?;
+
+    let held = toml::from_str(&tokio::fs::read_to_string(description).await?)?;
+    let data = tokio::fs::read(message).await?;
+
+    Ok((held, data))
+}
+
+async fn remove(config: &ServerConfig, token: &str) -> Result<()> {
+    let (description, message) =
+        held_files(config, token).ok_or_else(|| eyre!("Invalid token {token}"))?;
+
+    // Without its description the message is never looked at again
+    tokio::fs::remove_file(description).await?;
+    tokio::fs::remove_file(message).await?;
+
+    Ok(())
+}
+
+/// Sends a held post out to the list, as if it had just arrived.
+pub async fn approve(config: &ServerConfig, token: &str) -> Result<Held> {
+    let (held, data) = load(config, token).await?;
+    let list = config
+        .lists
+        .get(&held.list)
+        .ok_or_else(|| eyre!("{} is no longer a list", held.list))?;
+
+    let mail = Mail {
+        sender: held.sender.clone(),
+        recipients: vec![held.list.clone()],
+        data,
+        body: held.body,
+        smtputf8: held.smtputf8,
+        authenticated: held.authenticated.clone(),
+    };
+    let (post, recipients) = mail::list_post(config, &held.list, list, &mail).await?;
+    queue::enqueue(config, &post, recipients).await?;
+    remove(config, token).await?;
+
+    info!("Approved {token} for {}", held.list);
+    Ok(held)
+}
+
+/// Throws a held post away.
+pub async fn reject(config: &ServerConfig, token: &str) -> Result<Held> {
+    let (held, _) = load(config, token).await?;
+    remove(config, token).await?;
+
+    info!("Rejected {token} for {}", held.list);
+    Ok(held)
+}
+
+/// Every held post, oldest first.
+pub async fn held(config: &ServerConfig) -> Result<Vec<(String, Held)>> {
+    let dir = held_dir(config);
+    if !tokio::fs::try_exists(&dir).await? {
+        return Ok(Vec::new());
+    }
+
+    let mut posts = Vec::new();
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|x| x != "toml") {
+            continue;
+        }
+        let Some(token) = path.file_stem().map(|x| x.to_string_lossy().to_string()) else {
+            continue;
+        };
+
+        match tokio::fs::read_to_string(&path)
+            .await
+            .map(|x| toml::from_str(&x))
+        {
+            Ok(Ok(v)) => posts.push((token, v)),
+            _ => warn!("Couldn't read held post {}", path.display()),
+        }
+    }
+    posts.sort_by_key(|(_, x): &(String, Held)| x.created);
+
+    Ok(posts)
+}
+
+/// Forgets held posts nobody decided on within `held_lifetime`.
+pub async fn expire(config: &ServerConfig) -> Result<()> {
+    let lifetime = config.queue().held_lifetime();
+    let now = now();
+
+    for (token, held) in held(config).await? {
+        if now.saturating_sub(held.created) < lifetime {
+            continue;
+        }
+
+        info!("Held post {token} for {} expired", held.list);
+        if let Err(_e) = remove(config, &token).await {
+            warn!("Couldn't remove expired held post {token}");
+            debug!("Error: {_e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Acts on a reply to the notification about `token`. The sender is checked
+/// against the moderators, but envelope senders are easily forged: what
+/// really keeps others from deciding is that only the moderators are sent
+/// the 128-bit token.
+pub async fn reply(config: &ServerConfig, list: &str, token: &str, mail: &Mail) {
+    let Some(moderators) = config.lists.get(list).map(|x| x.moderators()) else {
+        return;
+    };
+    let from = mail.authenticated.as_deref().unwrap_or(&mail.sender);
+    if !moderators.iter().any(|x| posting::same_address(x, from)) {
+        info!("Ignoring moderation of {token} by {from}, who isn't a moderator");
+        return;
+    }
+
+    let Some(verdict) = verdict(&mail.message()) else {
+        info!("Ignoring reply about {token} from {from} without a decision");
+        return;
+    };
+
+    let result = match verdict {
+        Verdict::Approve => approve(config, token).await,
+        Verdict::Reject => reject(config, token).await,
+    };
+    if let Err(_e) = result {
+        warn!("Couldn't act on {from}'s decision about {token}");
+        debug!("Error: {_e}");
+    }
+}
+
+/// The decision in a reply: the first word of the first line of the plain
+/// text that isn't quoted.
+fn verdict(message: &Message) -> Option<Verdict> {
+    let body = message
+        .walk()
+        .into_iter()
+        .find(|x| x.content_type().mime_type == "text/plain")?
+        .text()?;
+
+    for line in body.lines() {
+        let line = line.trim();
+        if line.starts_with('>') {
+            continue;
+        }
+
+        let word: String = line
+            .chars()
+            .take_while(|x| x.is_alphabetic())
+            .collect::<String>()
+            .to_lowercase();
+        match word.as_str() {
+            "approve" | "approved" | "accept" | "yes" => return Some(Verdict::Approve),
+            "reject" | "rejected" | "discard" | "no" => return Some(Verdict::Reject),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Asks the moderators of the list to decide on a held post, attaching it.
+async fn notify(config: &ServerConfig, token: &str, held: &Held, data: &[u8]) {
+    let Some(list) = config.lists.get(&held.list) else {
+        return;
+    };
+    let moderators = list.moderators();
+    if moderators.is_empty() {
+        warn!(
+            "{} has no moderators, {token} can only be moderated from the command line",
+            held.list
+        );
+        return;
+    }
+
+    let hostname = &config.hostname;
+    let id = queue::new_id();
+    let boundary = format!("{id}/{hostname}");
+    let address = moderation_address(&held.list, token);
+    let sender = held.sender.trim_start_matches('<').trim_end_matches('>');
+    let subject = Header::parse(message::header_section(data))
+        .get("Subject")
+        .map(Field::text)
+        .unwrap_or_default();
+    let days = config.queue().held_lifetime() / (24 * 60 * 60);
+    let to = moderators
+        .iter()
+        .map(|x| format!("<{x}>"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let from = message::phrase(&format!("{} moderation", held.list));
+
+    let mut message = format!(
+        "From: {from} <{address}>\r\n\
+         To: {to}\r\n\
+         Subject: Held post to {list_name} from {sender}\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{id}@{hostname}>\r\n\
+         Auto-Submitted: auto-generated\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         A post to {list_name} is held for moderation.\r\n\
+         \r\n\
+         From: {sender}\r\n\
+         Subject: {subject}\r\n\
+         Size: {size} bytes\r\n\
+         \r\n\
+         Reply with \"approve\" to send it to the list, or with \"reject\" to\r\n\
+         throw it away. Without a decision it is thrown away after {days} days.\r\n\
+         Anyone who can reply to this message can decide, so don't forward it.\r\n\
+         \r\n\
+         On the server: mailing-list moderate approve {token}\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: message/rfc822\r\n\
+         \r\n",
+        list_name = held.list,
+        date = dsn::date(now()),
+        size = data.len(),
+    )
+    .into_bytes();
+    message.extend(data);
+    if !data.ends_with(b"\r\n") {
+        message.extend(b"\r\n");
+    }
+    message.extend(format!("--{boundary}--\r\n").as_bytes());
+
+    let mail = Mail {
+        sender: "<>".to_string(),
+        recipients: Vec::new(),
+        body: match message.is_ascii() {
+            true => Body::SevenBit,
+            false => Body::EightBitMime,
+        },
+        data: message,
+        smtputf8: false,
+        authenticated: None,
+    };
+    let recipients = moderators
+        .into_iter()
+        .map(|x| Recipient::new(x.clone(), queue::route(config, &x), None))
+        .collect();
+
+    if let Err(_e) = queue::enqueue(config, &mail, recipients).await {
+        warn!(
+            "Couldn't tell the moderators of {} about {token}",
+            held.list
+        );
+        debug!("Error: {_e}");
+    }
+}
+
+/// Runs `mailing-list moderate`.
+pub async fn run(config: &ServerConfig, action: Moderate) -> Result<()> {
+    match action {
+        Moderate::List => {
+            for (token, held) in held(config).await? {
+                let sender = held.sender.trim_start_matches('<').trim_end_matches('>');
+                println!(
+                    "{token} {} from {sender} at {}",
+                    held.list,
+                    dsn::date(held.created)
+                );
+            }
+        }
+        Moderate::Approve { token } => {
+            let held = approve(config, &token).await?;
+            println!("Approved {token}, it goes out to {} shortly", held.list);
+        }
+        Moderate::Reject { token } => {
+            let held = reject(config, &token).await?;
+            println!("Rejected {token} for {}", held.list);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(spool: &std::path::Path) -> ServerConfig {
+        toml::from_str(&format!(
+            "hostname = \"example.com\"\nplugins = []\n\
+             [queue]\nspool = {:?}\nheld_lifetime = 3600\n\
+             [lists.\"board@example.com\".Local]\nmembers = []\n",
+            spool.display().to_string()
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn accepts_only_real_tokens() {
+        let config = config(std::path::Path::new("/nonexistent"));
+        let token = new_token();
+        let address = moderation_address("board@example.com", &token);
+
+        assert_eq!(
+            parse_address(&config, &format!("<{address}>")),
+            Some(("board@example.com".to_string(), token.clone()))
+        );
+        assert!(held_files(&config, &token).is_some());
+
+        let upper = token.to_uppercase();
+        for token in [
+            "",
+            "../../../../etc/passwd",
+            &token[1..],
+            &upper,
+            &format!("{token}0"),
+        ] {
+            assert!(held_files(&config, token).is_none(), "{token}");
+            let address = moderation_address("board@example.com", token);
+            assert_eq!(parse_address(&config, &address), None);
+        }
+
+        // Only for lists that exist
+        let address = moderation_address("other@example.com", &token);
+        assert_eq!(parse_address(&config, &address), None);
+        assert_eq!(parse_address(&config, "<board@example.com>"), None);
+    }
+
+    fn reply(body: &str) -> Option<Verdict> {
+        verdict(&Message::parse(
+            format!("Subject: Re: Held post\r\n\r\n{body}").as_bytes(),
+        ))
+    }
+
+    #[test]
+    fn reads_verdicts() {
+        assert_eq!(reply("approve\r\n"), Some(Verdict::Approve));
+        assert_eq!(reply("Approved!\r\n"), Some(Verdict::Approve));
+        assert_eq!(reply("\r\n  Yes, thanks\r\n"), Some(Verdict::Approve));
+        assert_eq!(reply("REJECT\r\n"), Some(Verdict::Reject));
+        assert_eq!(reply("no\r\n"), Some(Verdict::Reject));
+
+        // Quoted lines are the notification, not the decision
+        assert_eq!(
+            reply("> Reply with \"approve\" to send it\r\nreject\r\n"),
+            Some(Verdict::Reject)
+        );
+        assert_eq!(reply("> approve\r\n>> approve\r\n"), None);
+        assert_eq!(reply("Thanks, I'll have a look\r\n"), None);
+        assert_eq!(reply("approval pending\r\n"), None);
+        assert_eq!(reply(""), None);
+
+        // The first plain text part counts, wherever it is
+        let message = Message::parse(
+            b"Content-Type: multipart/alternative; boundary=b\r\n\r\n\
+              --b\r\nContent-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n\r\n\
+              cmVqZWN0\r\n--b\r\nContent-Type: text/html\r\n\r\n<p>approve</p>\r\n--b--\r\n",
+        );
+        assert_eq!(verdict(&message), Some(Verdict::Reject));
+        let message = Message::parse(b"Content-Type: text/html\r\n\r\n<p>approve</p>\r\n");
+        assert_eq!(verdict(&message), None);
+    }
+
+    async fn store(config: &ServerConfig, created: u64) -> String {
+        let token = new_token();
+        let held = Held {
+            list: "board@example.com".to_string(),
+            sender: "<foo@example.net>".to_string(),
+            body: Body::SevenBit,
+            smtputf8: false,
+            authenticated: None,
+            created,
+        };
+        let (description, message) = held_files(config, &token).unwrap();
+        queue::write_atomic(&message, b"Subject: x\r\n\r\nx\r\n")
+            .await
+            .unwrap();
+        queue::write_atomic(&description, toml::to_string(&held).unwrap().as_bytes())
+            .await
+            .unwrap();
+
+        token
+    }
+
+    #[tokio::test]
+    async fn expires_old_posts() {
+        let spool = std::env::temp_dir().join(format!("moderation-{}", queue::new_id()));
+        let config = config(&spool);
+        tokio::fs::create_dir_all(held_dir(&config)).await.unwrap();
+
+        let old = store(&config, now() - 3600).await;
+        let new = store(&config, now() - 3000).await;
+        expire(&config).await.unwrap();
+
+        let tokens: Vec<String> = held(&config)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        assert_eq!(tokens, [new.as_str()]);
+        let (description, message) = held_files(&config, &old).unwrap();
+        assert!(!description.exists() && !message.exists());
+        let (description, message) = held_files(&config, &new).unwrap();
+        assert!(description.exists() && message.exists());
+
+        let _ = std::fs::remove_dir_all(spool);
+    }
+}
@@ -0,0 +1,546 @@
+use std::{fmt::Debug, future::Future, path::PathBuf, pin::Pin, sync::Arc, time::Duration};
+
+use color_eyre::eyre::{eyre, Result};
+use domain::{
+    base::{Name, Rtype},
+    rdata::Txt,
+    resolv::StubResolver,
+};
+use rustls::{ClientConfig, RootCertStore};
+use rustls_pki_types::ServerName;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, info, warn};
+
+use crate::{config::ServerConfig, queue};
+
+/// RFC 8461 §3.2, policies may be no larger than this.
+const MAX_POLICY_SIZE: u64 = 64 * 1024;
+
+/// RFC 8461 §3.2, a year.
+const MAX_MAX_AGE: u64 = 31557600;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+const HTTPS_PORT: u16 = 443;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Where policies come from. [`Internet`] asks DNS and the policy host of the
+/// domain, anything else can stand in for it.
+pub trait Fetcher: Debug + Send + Sync {
+    /// The `id` of the `_mta-sts` TXT record of `domain`, if it has one.
+    fn record<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<Option<String>>>;
+
+    /// The policy file of `domain`.
+    fn fetch<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<String>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    /// Deliver only to matching MX hosts over verified TLS.
+    Enforce,
+    /// Report what enforcing would have refused, but deliver anyway.
+    Testing,
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    pub id: String,
+    pub mode: Mode,
+    pub mx: Vec<String>,
+    pub max_age: u64,
+    pub fetched: u64,
+}
+
+impl Policy {
+    /// Parses a policy file (RFC 8461 §3.2) announced with `id`.
+    pub fn parse(id: &str, text: &str, fetched: u64) -> Result<Self> {
+        let mut version = None;
+        let mut mode = None;
+        let mut mx = Vec::new();
+        let mut max_age = None;
+
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+
+            match key.trim() {
+                "version" => version = Some(value.to_string()),
+                "mode" => {
+                    mode = Some(match value {
+                        "enforce" => Mode::Enforce,
+                        "testing" => Mode::Testing,
+                        "none" => Mode::None,
+                        _ => return Err(eyre!("Unknown mode {value}")),
+                    })
+                }
+                "mx" => mx.push(value.to_ascii_lowercase()),
+                "max_age" => max_age = Some(value.parse::<u64>()?.min(MAX_MAX_AGE)),
+                _ => {}
+            }
+        }
+
+        if version.as_deref() != Some("STSv1") {
+            return Err(eyre!("Not an STSv1 policy"));
+        }
+        let mode = mode.ok_or(eyre!("Policy has no mode"))?;
+        if mode != Mode::None && mx.is_empty() {
+            return Err(eyre!("Policy has no mx"));
+        }
+
+        Ok(Self {
+            id: id.to_string(),
+            mode,
+            mx,
+            max_age: max_age.ok_or(eyre!("Policy has no max_age"))?,
+            fetched,
+        })
+    }
+
+    pub fn is_valid(&self, now: u64) -> bool {
+        now < self.fetched.saturating_add(self.max_age)
+    }
+
+    /// Whether `host` is one of the MX hosts the policy allows. A `*.` pattern
+    /// matches exactly one more label.
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        self.mx
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(suffix) => host
+                    .split_once('.')
+                    .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+                None => host == *pattern,
+            })
+    }
+}
+
+/// Whether `domain` looks like a host name, and so is safe to use in a path
+/// or URL.
+fn is_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && label
+                    .bytes()
+                    .all(|x| x.is_ascii_alphanumeric() || x == b'-')
+        })
+}
+
+fn cache_file(config: &ServerConfig, domain: &str) -> PathBuf {
+    config
+        .queue()
+        .spool()
+        .join("mta-sts")
+        .join(format!("{domain}.toml"))
+}
+
+async fn load(config: &ServerConfig, domain: &str) -> Option<Policy> {
+    let text = tokio::fs::read_to_string(cache_file(config, domain))
+        .await
+        .ok()?;
+    let policy: Policy = toml::from_str(&text).ok()?;
+
+    policy.is_valid(queue::now()).then_some(policy)
+}
+
+async fn save(config: &ServerConfig, domain: &str, policy: &Policy) -> Result<()> {
+    let file = cache_file(config, domain);
+
+    if let Some(parent) = file.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(file, toml::to_string(policy)?).await?;
+
+    Ok(())
+}
+
+/// The policy in effect for `domain`, if any (RFC 8461 §5.1). Policies are
+/// cached in the spool and only fetched again when the TXT record announces
+/// a new one, a cached policy stays in effect until it expires even if the
+/// record disappears or the fetch fails.
+pub async fn policy(config: &ServerConfig, domain: &str, fetcher: &dyn Fetcher) -> Option<Policy> {
+    let domain = domain.to_ascii_lowercase();
+    if !is_domain(&domain) {
+        debug!("Not looking up an MTA-STS policy for {domain}");
+        return None;
+    }
+    let cached = load(config, &domain).await;
+
+    let id = match fetcher.record(&domain).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return cached,
+        Err(_e) => {
+            debug!("Couldn't look up MTA-STS record for {domain}: {_e}");
+            return cached;
+        }
+    };
+
+    if cached.as_ref().is_some_and(|x| x.id == id) {
+        return cached;
+    }
+
+    let policy = match fetcher.fetch(&domain).await {
+        Ok(text) => Policy::parse(&id, &text, queue::now()),
+        Err(e) => Err(e),
+    };
+
+    match policy {
+        Ok(policy) => {
+            info!("Using MTA-STS policy {id} for {domain}");
+            if let Err(_e) = save(config, &domain, &policy).await {
+                warn!("Couldn't cache MTA-STS policy for {domain}");
+                debug!("Error: {_e}");
+            }
+            Some(policy)
+        }
+        Err(_e) => {
+            warn!("Couldn't fetch MTA-STS policy for {domain}");
+            debug!("Error: {_e}");
+            cached
+        }
+    }
+}
+
+/// Looks policies up in DNS and fetches them over HTTPS, like RFC 8461 says.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Internet;
+
+impl Fetcher for Internet {
+    fn record<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(async move {
+            let resolver = StubResolver::new();
+            let name = Name::bytes_from_str(&format!("_mta-sts.{domain}"))?;
+            let answer = resolver.query((&name, Rtype::TXT)).await?;
+
+            let mut ids = Vec::new();
+            for record in answer.answer()?.limit_to::<Txt<_>>() {
+                let text: Vec<u8> = record?.data().iter().flatten().copied().collect();
+                let text = String::from_utf8_lossy(&text);
+
+                if !text.starts_with("v=STSv1") {
+                    continue;
+                }
+                ids.extend(
+                    text.split(';')
+                        .filter_map(|x| x.trim().strip_prefix("id="))
+                        .map(|x| x.to_string()),
+                );
+            }
+
+            // More than one record is the same as none (RFC 8461 §3.1)
+            Ok(match ids.len() {
+                1 => ids.pop(),
+                _ => None,
+            })
+        })
+    }
+
+    fn fetch<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let host = format!("mta-sts.{domain}");
+            let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let config = ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+            timeout(FETCH_TIMEOUT, get(&host, HTTPS_PORT, Arc::new(config)))
+                .await
+                .map_err(|_| eyre!("Fetching the policy from {host} timed out"))?
+        })
+    }
+}
+
+/// A bare HTTP/1.0 GET of the policy, so the body is never chunked. Redirects
+/// must not be followed, so anything but 200 is an error.
+async fn get(host: &str, port: u16, config: Arc<ClientConfig>) -> Result<String> {
+    let stream = TcpStream::connect((host, port)).await?;
+    let server_name = ServerName::try_from(host.to_string())?;
+    let mut stream = TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await?;
+
+    stream
+        .write_all(
+            format!("GET /.well-known/mta-sts.txt HTTP/1.0\r\nHost: {host}\r\n\r\n").as_bytes(),
+        )
+        .await?;
+
+    // One byte more than allowed tells a response that is too large from one
+    // that just fits
+    let mut response = Vec::new();
+    stream
+        .take(MAX_POLICY_SIZE + 4096 + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() as u64 > MAX_POLICY_SIZE + 4096 {
+        return Err(eyre!("The response from {host} is too large"));
+    }
+    let response = String::from_utf8(response)?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or(eyre!("Malformed response from {host}"))?;
+    let mut lines = head.lines();
+    let status = lines.next().unwrap_or_default();
+
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(eyre!("{host} answered {status}"));
+    }
+
+    // RFC 8461 §3.3
+    let content_type = lines
+        .filter_map(|x| x.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Type"))
+        .map(|(_, value)| value.split(';').next().unwrap_or_default().trim())
+        .unwrap_or_default();
+    if !content_type.eq_ignore_ascii_case("text/plain") {
+        return Err(eyre!("{host} sent the policy as {content_type:?}"));
+    }
+
+    if body.len() as u64 > MAX_POLICY_SIZE {
+        return Err(eyre!("The policy from {host} is too large"));
+    }
+
+    Ok(body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+
+    const POLICY: &str = "version: STSv1\r\nmode: enforce\r\nmx: mail.example.com\r\n\
+                          mx: *.example.net\r\nmax_age: 86400\r\n";
+
+    #[derive(Debug)]
+    struct Fake {
+        id: std::sync::Mutex<Option<String>>,
+        fetches: AtomicUsize,
+    }
+
+    impl Fetcher for Fake {
+        fn record<'a>(&'a self, _domain: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+            let id = self.id.lock().unwrap().clone();
+            Box::pin(async move { Ok(id) })
+        }
+
+        fn fetch<'a>(&'a self, _domain: &'a str) -> BoxFuture<'a, Result<String>> {
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move { Ok(POLICY.to_string()) })
+        }
+    }
+
+    #[test]
+    fn parses_policies() {
+        let policy = Policy::parse("1", POLICY, 0).unwrap();
+
+        assert_eq!(policy.mode, Mode::Enforce);
+        assert_eq!(policy.mx, ["mail.example.com", "*.example.net"]);
+        assert_eq!(policy.max_age, 86400);
+        assert!(policy.is_valid(86399));
+        assert!(!policy.is_valid(86400));
+
+        assert!(Policy::parse("1", "version: STSv2\nmode: enforce\nmx: a\nmax_age: 1", 0).is_err());
+        assert!(Policy::parse("1", "version: STSv1\nmode: enforce\nmax_age: 1", 0).is_err());
+    }
+
+    #[test]
+    fn matches_mx_patterns() {
+        let policy = Policy::parse("1", POLICY, 0).unwrap();
+
+        assert!(policy.matches("mail.example.com."));
+        assert!(policy.matches("MX1.example.net"));
+        assert!(!policy.matches("example.net"));
+        assert!(!policy.matches("a.b.example.net"));
+        assert!(!policy.matches("mail.example.org"));
+    }
+
+    #[tokio::test]
+    async fn caches_policies_until_the_id_changes() {
+        let spool = std::env::temp_dir().join(format!("mta-sts-{}", queue::new_id()));
+        let config: ServerConfig = toml::from_str(&format!(
+            "hostname = \"localhost\"\nplugins = []\n[lists]\n[queue]\nspool = {:?}\n",
+            spool.display().to_string()
+        ))
+        .unwrap();
+        let fetcher = Fake {
+            id: std::sync::Mutex::new(Some("1".to_string())),
+            fetches: AtomicUsize::new(0),
+        };
+
+        let first = policy(&config, "example.com", &fetcher).await.unwrap();
+        let second = policy(&config, "example.com", &fetcher).await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 1);
+
+        // A cached policy outlives its record
+        *fetcher.id.lock().unwrap() = None;
+        assert!(policy(&config, "example.com", &fetcher).await.is_some());
+        assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 1);
+
+        *fetcher.id.lock().unwrap() = Some("2".to_string());
+        let third = policy(&config, "example.com", &fetcher).await.unwrap();
+        assert_eq!(third.id, "2");
+        assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 2);
+
+        let _ = std::fs::remove_dir_all(spool);
+    }
+
+    #[tokio::test]
+    async fn refuses_odd_domains() {
+        let config: ServerConfig =
+            toml::from_str("hostname = \"localhost\"\nplugins = []\n[lists]\n").unwrap();
+        let fetcher = Fake {
+            id: std::sync::Mutex::new(Some("1".to_string())),
+            fetches: AtomicUsize::new(0),
+        };
+
+        for domain in [
+            "../../etc/passwd",
+            "example.com/x",
+            "",
+            "a..b",
+            "-a.example.com",
+        ] {
+            assert!(!is_domain(domain));
+            assert_eq!(policy(&config, domain, &fetcher).await, None);
+        }
+        assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 0);
+        assert!(is_domain("mta-sts.example.com"));
+    }
+
+    /// Answers one request as `localhost` with `response`, returning the port
+    /// and what the client sent.
+    async fn serve(response: Vec<u8>) -> (u16, tokio::task::JoinHandle<String>) {
+        let certificates =
+            CertificateDer::pem_slice_iter(include_bytes!("../testdata/localhost.pem"))
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .unwrap();
+        let key =
+            PrivateKeyDer::from_pem_slice(include_bytes!("../testdata/localhost.key")).unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let Ok(mut stream) = TlsAcceptor::from(Arc::new(config)).accept(stream).await else {
+                return String::new();
+            };
+
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buffer = [0; 1024];
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend(&buffer[..n]),
+                }
+            }
+            // The client may hang up on large responses
+            let _ = stream.write_all(&response).await;
+            let _ = stream.shutdown().await;
+
+            String::from_utf8(request).unwrap()
+        });
+
+        (port, server)
+    }
+
+    fn client() -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(include_bytes!("../testdata/ca.pem")).unwrap())
+            .unwrap();
+
+        Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    }
+
+    async fn fetch(response: &str) -> Result<String> {
+        let (port, _) = serve(response.as_bytes().to_vec()).await;
+        get("localhost", port, client()).await
+    }
+
+    #[tokio::test]
+    async fn fetches_policies_over_https() {
+        let response =
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{POLICY}");
+        let (port, server) = serve(response.into_bytes()).await;
+
+        assert_eq!(get("localhost", port, client()).await.unwrap(), POLICY);
+        assert_eq!(
+            server.await.unwrap(),
+            "GET /.well-known/mta-sts.txt HTTP/1.0\r\nHost: localhost\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_responses() {
+        let redirect = "HTTP/1.1 301 Moved Permanently\r\nLocation: https://example.com/\r\n\r\n";
+        assert!(fetch(redirect).await.is_err());
+        assert!(
+            fetch("HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\n\r\n")
+                .await
+                .is_err()
+        );
+        assert!(fetch(&format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n{POLICY}"
+        ))
+        .await
+        .is_err());
+        assert!(fetch(&format!("HTTP/1.1 200 OK\r\n\r\n{POLICY}"))
+            .await
+            .is_err());
+        assert!(fetch(POLICY).await.is_err());
+
+        let large = "x".repeat(MAX_POLICY_SIZE as usize + 1);
+        assert!(fetch(&format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n{large}"
+        ))
+        .await
+        .is_err());
+        let large = "x".repeat(MAX_POLICY_SIZE as usize);
+        assert!(fetch(&format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n{large}"
+        ))
+        .await
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn refuses_untrusted_certificates() {
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n{POLICY}");
+        let (port, _) = serve(response.into_bytes()).await;
+        let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        assert!(get("localhost", port, Arc::new(config)).await.is_err());
+    }
+}
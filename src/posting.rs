@@ -0,0 +1,169 @@
+use color_eyre::eyre::Result;
+
+use crate::config::{List, PostingAction, PostingPolicy};
+
+/// What happens to a post to a list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    Reject,
+    Discard,
+    Hold,
+}
+
+impl From<PostingAction> for Decision {
+    fn from(action: PostingAction) -> Self {
+        match action {
+            PostingAction::Reject => Self::Reject,
+            PostingAction::Discard => Self::Discard,
+            PostingAction::Hold => Self::Hold,
+        }
+    }
+}
+
+pub fn same_address(a: &str, b: &str) -> bool {
+    let bare = |x: &str| {
+        x.trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_string()
+    };
+
+    bare(a).eq_ignore_ascii_case(&bare(b))
+}
+
+/// Applies the posting policy of `list` to a post from `sender`. A client
+/// that logged in posts as whoever it authenticated as.
+pub async fn decide(list: &List, sender: &str, authenticated: Option<&str>) -> Result<Decision> {
+    let policy = list.posting();
+    if policy == PostingPolicy::Open {
+        return Ok(Decision::Accept);
+    }
+
+    let poster = match (authenticated, list.authenticated_only()) {
+        (Some(v), _) => v,
+        (None, false) => sender,
+        (None, true) => return Ok(list.refused_posts().into()),
+    };
+    // The null sender is a bounce, which must never go out to a list
+    if poster.is_empty() || poster == "<>" {
+        return Ok(list.refused_posts().into());
+    }
+
+    let allowed = list
+        .allowed_senders()
+        .iter()
+        .chain(&list.moderators())
+        .any(|x| same_address(x, poster));
+    let accepted = match policy {
+        PostingPolicy::Open => true,
+        PostingPolicy::MembersOnly if allowed => true,
+        PostingPolicy::MembersOnly => list
+            .get_members()
+            .await?
+            .iter()
+            .any(|x| same_address(x, poster)),
+        PostingPolicy::Allowlist => allowed,
+        PostingPolicy::Moderated => {
+            return Ok(match allowed {
+                true => Decision::Accept,
+                false => Decision::Hold,
+            })
+        }
+    };
+
+    Ok(match accepted {
+        true => Decision::Accept,
+        false => list.refused_posts().into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(settings: &str) -> List {
+        toml::from_str(&format!(
+            "owner = \"owner@example.com\"\n{settings}\n\
+             [Local]\nmembers = [\"member@example.com\", \"<Other@Example.com>\"]\n"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn compares_addresses() {
+        assert!(same_address("<foo@example.com>", "foo@example.com"));
+        assert!(same_address(" Foo@EXAMPLE.com ", "<foo@example.com>"));
+        assert!(!same_address("foo@example.com", "foo@example.org"));
+        assert!(!same_address("foo@example.com", ""));
+    }
+
+    #[tokio::test]
+    async fn applies_policies() {
+        use Decision::*;
+
+        let open = "posting = \"Open\"";
+        let members = "posting = \"MembersOnly\"";
+        let allowlist = "posting = \"Allowlist\"\nallowed_senders = [\"chair@example.org\"]";
+        let moderated = "posting = \"Moderated\"\nmoderators = [\"mod@example.org\"]";
+        let authenticated_only = "posting = \"MembersOnly\"\nauthenticated_only = true";
+        let discarding = "posting = \"MembersOnly\"\nrefused_posts = \"Discard\"";
+        let holding = "posting = \"Allowlist\"\nrefused_posts = \"Hold\"";
+
+        let cases = [
+            // Settings, envelope sender, authenticated as, decision
+            (open, "<stranger@example.net>", None, Accept),
+            (open, "<>", None, Accept),
+            // Lists are open unless they say otherwise
+            ("", "<stranger@example.net>", None, Accept),
+            (members, "<member@example.com>", None, Accept),
+            (members, "<MEMBER@example.com>", None, Accept),
+            (members, "other@example.com", None, Accept),
+            (members, "<stranger@example.net>", None, Reject),
+            (members, "<>", None, Reject),
+            (members, "", None, Reject),
+            // The owner moderates unless someone else does
+            (members, "<owner@example.com>", None, Accept),
+            // Logging in decides who posts, whatever the envelope says
+            (
+                members,
+                "<stranger@example.net>",
+                Some("member@example.com"),
+                Accept,
+            ),
+            (
+                members,
+                "<member@example.com>",
+                Some("stranger@example.net"),
+                Reject,
+            ),
+            (members, "<>", Some("member@example.com"), Accept),
+            (allowlist, "<chair@example.org>", None, Accept),
+            (allowlist, "<owner@example.com>", None, Accept),
+            (allowlist, "<member@example.com>", None, Reject),
+            (moderated, "<mod@example.org>", None, Accept),
+            (moderated, "<member@example.com>", None, Hold),
+            (moderated, "<owner@example.com>", None, Hold),
+            (moderated, "<>", None, Reject),
+            (authenticated_only, "<member@example.com>", None, Reject),
+            (
+                authenticated_only,
+                "<stranger@example.net>",
+                Some("member@example.com"),
+                Accept,
+            ),
+            (discarding, "<stranger@example.net>", None, Discard),
+            (holding, "<member@example.com>", None, Hold),
+        ];
+
+        for (settings, sender, authenticated, expected) in cases {
+            let decision = decide(&list(settings), sender, authenticated)
+                .await
+                .unwrap();
+            assert_eq!(
+                decision, expected,
+                "{settings:?} {sender} {authenticated:?}"
+            );
+        }
+    }
+}
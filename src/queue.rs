@@ -0,0 +1,362 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, time::timeout};
+use tracing::{debug, info, warn};
+
+use crate::{
+    bounce,
+    config::{get_config, ServerConfig},
+    dsn,
+    mail::{Body, Mail},
+    moderation,
+    send_mail::{self, send_group, Delivery, DeliveryError, DeliveryResult, Relay},
+};
+
+/// Wakes the queue runner when new mail is spooled.
+static QUEUE: Notify = Notify::const_new();
+
+/// The longest the runner sleeps before rescanning the spool, so that entries
+/// added behind its back (e.g. copied in by hand) are picked up eventually.
+const MAX_IDLE: u64 = 60;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Where a recipient is delivered: to the MX of its domain, or through the
+/// configured forwarding server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Route {
+    Mx,
+    Forward,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    Pending,
+    Delivered,
+    /// Rejected permanently by the remote side.
+    Failed,
+    /// Still failing temporarily when the queue lifetime ran out.
+    Expired,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recipient {
+    pub address: String,
+    pub route: Route,
+    /// The list this recipient is a member of, if it came from one.
+    pub list: Option<String>,
+    pub status: Status,
+    pub attempts: u32,
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+impl Recipient {
+    pub fn new(address: String, route: Route, list: Option<String>) -> Self {
+        Self {
+            address,
+            route,
+            list,
+            status: Status::Pending,
+            attempts: 0,
+            next_attempt: 0,
+            last_error: None,
+        }
+    }
+}
+
+/// Everything about a queued message except its content, stored next to it
+/// as `<id>.toml`. The content lives in `<id>.eml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub sender: String,
+    pub body: Body,
+    pub smtputf8: bool,
+    pub created: u64,
+    pub recipients: Vec<Recipient>,
+}
+
+impl Envelope {
+    fn is_done(&self) -> bool {
+        self.recipients.iter().all(|x| x.status != Status::Pending)
+    }
+
+    fn next_attempt(&self) -> Option<u64> {
+        self.recipients
+            .iter()
+            .filter(|x| x.status == Status::Pending)
+            .map(|x| x.next_attempt)
+            .min()
+    }
+}
+
+/// Where mail we originate ourselves, like bounces, should go.
+pub fn route(config: &ServerConfig, address: &str) -> Route {
+    match config.forwards(address) {
+        true => Route::Forward,
+        false => Route::Mx,
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn new_id() -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let counter = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    format!("{time:x}-{}-{counter:x}", std::process::id())
+}
+
+/// Writes through a temporary file so a crash never leaves half a file behind.
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await?;
+
+    Ok(())
+}
+
+async fn save_envelope(spool: &Path, id: &str, envelope: &Envelope) -> Result<()> {
+    let contents = toml::to_string(envelope)?;
+    write_atomic(&spool.join(format!("{id}.toml")), contents.as_bytes()).await
+}
+
+/// Spools `mail` for every recipient in `deliveries` and wakes the runner.
+pub async fn enqueue(config: &ServerConfig, mail: &Mail, deliveries: Vec<Recipient>) -> Result<()> {
+    if deliveries.is_empty() {
+        return Ok(());
+    }
+
+    let queue = config.queue();
+    let spool = queue.spool();
+    tokio::fs::create_dir_all(spool).await?;
+
+    let id = new_id();
+    let created = now();
+    let envelope = Envelope {
+        sender: mail.sender.clone(),
+        body: mail.body,
+        smtputf8: mail.smtputf8,
+        created,
+        recipients: deliveries
+            .into_iter()
+            .map(|x| Recipient {
+                next_attempt: created,
+                ..x
+            })
+            .collect(),
+    };
+
+    // The envelope is written last, an `.eml` without one is never picked up
+    write_atomic(&spool.join(format!("{id}.eml")), &mail.data).await?;
+    save_envelope(spool, &id, &envelope).await?;
+
+    info!("Queued {id} for {} recipients", envelope.recipients.len());
+    QUEUE.notify_one();
+
+    Ok(())
+}
+
+/// Delivers spooled mail forever. The configuration is reread on every pass
+/// so that changes apply without a restart, like for incoming connections.
+pub async fn run(config_file: Option<String>, mut config: ServerConfig) {
+    loop {
+        match get_config(config_file.as_deref()) {
+            Ok(v) => config = v,
+            Err(_e) => {
+                warn!("Couldn't reload configuration for the queue, using the old one");
+                debug!("Error: {_e}");
+            }
+        };
+
+        if let Err(_e) = moderation::expire(&config).await {
+            warn!("Couldn't expire held posts");
+            debug!("Error: {_e}");
+        }
+
+        let next_attempt = match process(&config).await {
+            Ok(v) => v,
+            Err(_e) => {
+                warn!("Couldn't process the queue");
+                debug!("Error: {_e}");
+                None
+            }
+        };
+
+        let idle = next_attempt
+            .map(|x| x.saturating_sub(now()))
+            .unwrap_or(MAX_IDLE)
+            .min(MAX_IDLE);
+        let _ = timeout(Duration::from_secs(idle), QUEUE.notified()).await;
+    }
+}
+
+/// Goes through the spool once, returning when the next retry is due.
+async fn process(config: &ServerConfig) -> Result<Option<u64>> {
+    let queue = config.queue();
+    let spool = queue.spool();
+
+    if !tokio::fs::try_exists(spool).await? {
+        return Ok(None);
+    }
+
+    let mut next_attempt: Option<u64> = None;
+    let mut entries = tokio::fs::read_dir(spool).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|x| x != "toml") {
+            continue;
+        }
+
+        match deliver(config, &path).await {
+            Ok(Some(v)) => next_attempt = Some(next_attempt.map_or(v, |x| x.min(v))),
+            Ok(None) => {}
+            Err(_e) => {
+                warn!("Couldn't process queued mail {}", path.display());
+                debug!("Error: {_e}");
+            }
+        }
+    }
+
+    Ok(next_attempt)
+}
+
+/// Attempts every recipient of one queued message that is due, and removes
+/// the message once nobody is left pending.
+async fn deliver(config: &ServerConfig, path: &Path) -> Result<Option<u64>> {
+    let queue = config.queue();
+    let spool = queue.spool();
+    let id = path
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let data_path: PathBuf = spool.join(format!("{id}.eml"));
+
+    let mut envelope: Envelope = toml::from_str(&tokio::fs::read_to_string(path).await?)?;
+    let now = now();
+
+    let due = |x: &Recipient| x.status == Status::Pending && x.next_attempt <= now;
+    if envelope.recipients.iter().any(due) {
+        let mail = Mail {
+            sender: envelope.sender.clone(),
+            recipients: Vec::new(),
+            data: tokio::fs::read(&data_path).await?,
+            body: envelope.body,
+            smtputf8: envelope.smtputf8,
+            authenticated: None,
+        };
+
+        let mx: Vec<Delivery> = envelope
+            .recipients
+            .iter()
+            .filter(|x| due(x) && x.route == Route::Mx)
+            .map(|x| Delivery {
+                sender: sender_for(config, &envelope.sender, x),
+                to: x.address.clone(),
+                list: x.list.clone(),
+            })
+            .collect();
+        let mut results = send_group(config, &mail, &mx).await.into_iter();
+        let mut failed = Vec::new();
+
+        for recipient in envelope.recipients.iter_mut().filter(|x| due(x)) {
+            let result = match recipient.route {
+                // Never count a recipient as delivered without a result
+                Route::Mx => results.next().unwrap_or(Err(DeliveryError::Transient(
+                    "No delivery result".to_string(),
+                ))),
+                Route::Forward => forward(config, &mail, &recipient.address).await,
+            };
+
+            match result {
+                Ok(_) => recipient.status = Status::Delivered,
+                Err(e @ DeliveryError::Permanent(_)) => {
+                    warn!("Giving up on {} for {id}: {e}", recipient.address);
+                    recipient.attempts += 1;
+                    recipient.last_error = Some(e.to_string());
+                    recipient.status = Status::Failed;
+                    failed.push(recipient.clone());
+
+                    if let Some(list) = &recipient.list {
+                        bounce::record(config, list, &recipient.address).await;
+                    }
+                }
+                Err(e @ DeliveryError::Transient(_)) => {
+                    recipient.attempts += 1;
+                    recipient.last_error = Some(e.to_string());
+
+                    if now.saturating_sub(envelope.created) >= queue.max_lifetime() {
+                        warn!("Giving up on {} for {id}: {e}", recipient.address);
+                        recipient.status = Status::Expired;
+                        failed.push(recipient.clone());
+                    } else {
+                        recipient.next_attempt = now + queue.retry_delay(recipient.attempts);
+                        info!(
+                            "Couldn't send {id} to {}, retrying in {}s: {e}",
+                            recipient.address,
+                            recipient.next_attempt - now
+                        );
+                    }
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            dsn::notify(config, &envelope.sender, &failed, &mail.data).await;
+        }
+    }
+
+    if envelope.is_done() {
+        debug!("{id} is done, removing it from the queue");
+        tokio::fs::remove_file(path).await?;
+        tokio::fs::remove_file(&data_path).await?;
+        return Ok(None);
+    }
+
+    save_envelope(spool, &id, &envelope).await?;
+
+    Ok(envelope.next_attempt())
+}
+
+/// The envelope sender for one recipient, a VERP address for members of
+/// lists with bounce processing.
+fn sender_for(config: &ServerConfig, sender: &str, recipient: &Recipient) -> String {
+    let list = recipient
+        .list
+        .as_ref()
+        .filter(|x| config.lists.get(*x).is_some_and(|x| x.verp()));
+
+    match list {
+        Some(list) if sender != "<>" => bounce::verp_sender(list, &recipient.address),
+        _ => sender.to_string(),
+    }
+}
+
+async fn forward(config: &ServerConfig, mail: &Mail, to: &str) -> DeliveryResult {
+    let Some(forwarding) = config.forwarding.clone().filter(|x| x.enable) else {
+        return Err(DeliveryError::Permanent(
+            "Forwarding is no longer enabled".to_string(),
+        ));
+    };
+
+    let relay = Relay::new(forwarding).await?;
+
+    send_mail::send(&config.hostname, mail, to, &relay).await
+}
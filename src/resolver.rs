@@ -0,0 +1,348 @@
+use std::{fmt::Display, future::Future, net::IpAddr, pin::Pin};
+
+use domain::{
+    base::{iana::Rcode, Name, Rtype},
+    rdata::{AllRecordData, Mx},
+    resolv::StubResolver,
+};
+use rand::seq::SliceRandom;
+use tracing::debug;
+
+/// A host accepting mail for a domain, with every address it resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailServer {
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+}
+
+impl MailServer {
+    /// Whether the server was given as an address rather than a host name, so
+    /// there is no name to check its certificate against.
+    pub fn is_literal(&self) -> bool {
+        self.name.parse::<IpAddr>().is_ok() || self.name.starts_with('[')
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The domain doesn't exist.
+    NoDomain(String),
+    /// The domain publishes a null MX, it never accepts mail (RFC 7505).
+    NullMx(String),
+    /// The domain has neither MX records nor addresses.
+    NoMailServer(String),
+    /// DNS failed, or none of the MX hosts could be resolved. Likely temporary.
+    Lookup(String),
+}
+
+impl Error {
+    pub fn is_permanent(&self) -> bool {
+        !matches!(self, Self::Lookup(_))
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoDomain(domain) => write!(f, "5.1.2 Domain {domain} does not exist"),
+            Self::NullMx(domain) => write!(f, "5.1.10 Domain {domain} does not accept mail"),
+            Self::NoMailServer(domain) => write!(f, "5.1.2 Domain {domain} has no mail server"),
+            Self::Lookup(reason) => write!(f, "4.4.3 {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+type Result<T> = std::result::Result<T, Error>;
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Where mail servers are looked up. [`Dns`] asks the configured resolver,
+/// anything else can stand in for it.
+pub trait Lookup: Send + Sync {
+    /// The MX records of `domain` as preference and exchange, a null MX
+    /// having `.` as its exchange.
+    fn mx<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<Vec<(u16, String)>>>;
+
+    /// Every IPv6 and IPv4 address of `host`, in that order.
+    fn addresses<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>>>;
+}
+
+pub struct Dns;
+
+impl Lookup for Dns {
+    fn mx<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<Vec<(u16, String)>>> {
+        Box::pin(async move {
+            let resolver = StubResolver::new();
+            let name =
+                Name::bytes_from_str(domain).map_err(|_| Error::NoDomain(domain.to_string()))?;
+
+            let answer = resolver
+                .query((&name, Rtype::MX))
+                .await
+                .map_err(|e| Error::Lookup(format!("MX lookup for {domain} failed: {e}")))?;
+
+            match answer.header().rcode() {
+                Rcode::NOERROR => {}
+                Rcode::NXDOMAIN => return Err(Error::NoDomain(domain.to_string())),
+                rcode => {
+                    return Err(Error::Lookup(format!(
+                        "MX lookup for {domain} failed: {rcode}"
+                    )))
+                }
+            }
+
+            let mut hosts = Vec::new();
+            let records = answer
+                .answer()
+                .map_err(|e| Error::Lookup(format!("Invalid MX answer for {domain}: {e}")))?
+                .limit_to::<Mx<_>>();
+
+            for record in records {
+                let record = match record {
+                    Ok(v) => v,
+                    Err(_e) => {
+                        debug!("Skipping invalid MX record for {domain}: {_e}");
+                        continue;
+                    }
+                };
+
+                let exchange = match record.data().exchange().is_root() {
+                    true => ".".to_string(),
+                    false => record.data().exchange().to_string(),
+                };
+                hosts.push((record.data().preference(), exchange));
+            }
+
+            Ok(hosts)
+        })
+    }
+
+    fn addresses<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let resolver = StubResolver::new();
+            let name = Name::bytes_from_str(host)
+                .map_err(|e| Error::Lookup(format!("Invalid mail server {host}: {e}")))?;
+
+            // Queried one by one, as lookup_host takes a SERVFAIL for a
+            // name without addresses
+            let mut addresses = Vec::new();
+            for rtype in [Rtype::AAAA, Rtype::A] {
+                let answer = resolver
+                    .query((&name, rtype))
+                    .await
+                    .map_err(|e| Error::Lookup(format!("Looking up {host} failed: {e}")))?;
+
+                match answer.header().rcode() {
+                    Rcode::NOERROR => {}
+                    Rcode::NXDOMAIN => return Ok(Vec::new()),
+                    rcode => {
+                        return Err(Error::Lookup(format!("Looking up {host} failed: {rcode}")))
+                    }
+                }
+
+                let records = answer
+                    .answer()
+                    .map_err(|e| Error::Lookup(format!("Invalid answer for {host}: {e}")))?;
+                for record in records.limit_to::<AllRecordData<_, _>>().flatten() {
+                    match record.data() {
+                        AllRecordData::Aaaa(v) => addresses.push(IpAddr::V6(v.addr())),
+                        AllRecordData::A(v) => addresses.push(IpAddr::V4(v.addr())),
+                        _ => {}
+                    }
+                }
+            }
+
+            Ok(addresses)
+        })
+    }
+}
+
+/// The servers to try for `domain`, most preferred first (RFC 5321 §5.1).
+/// Servers with the same preference are shuffled to spread the load, and a
+/// domain without MX records is its own mail server. Address literals like
+/// `[192.0.2.1]` or `[IPv6:2001:db8::1]` are returned as they are.
+pub async fn mail_servers(domain: &str) -> Result<Vec<MailServer>> {
+    if let Some(literal) = domain.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+        // The tag is case-insensitive, like the rest of the domain
+        let address = match literal.get(..5) {
+            Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => &literal[5..],
+            _ => literal,
+        };
+        return match address.parse() {
+            Ok(v) => Ok(vec![MailServer {
+                name: domain.to_string(),
+                addresses: vec![v],
+            }]),
+            Err(_) => Err(Error::NoDomain(domain.to_string())),
+        };
+    }
+
+    resolve(domain, &Dns).await
+}
+
+async fn resolve(domain: &str, lookup: &dyn Lookup) -> Result<Vec<MailServer>> {
+    let mut hosts = lookup.mx(domain).await?;
+    if hosts.iter().any(|(_, exchange)| exchange == ".") {
+        return Err(Error::NullMx(domain.to_string()));
+    }
+
+    let implicit = hosts.is_empty();
+    if implicit {
+        debug!("{domain} has no MX records, using its address");
+        hosts.push((0, domain.to_string()));
+    }
+
+    {
+        let mut rng = rand::thread_rng();
+        hosts.shuffle(&mut rng);
+    }
+    // Stable, so the shuffle is kept within each preference
+    hosts.sort_by_key(|(preference, _)| *preference);
+
+    let mut servers = Vec::new();
+    let mut error = None;
+    for (_, host) in hosts {
+        let addresses = match lookup.addresses(&host).await {
+            Ok(v) if !v.is_empty() => v,
+            Ok(_) => {
+                debug!("Mail server {host} for {domain} has no addresses");
+                continue;
+            }
+            Err(e) => {
+                debug!("Couldn't resolve mail server {host} for {domain}: {e}");
+                error = Some(e);
+                continue;
+            }
+        };
+
+        servers.push(MailServer {
+            name: host,
+            addresses,
+        });
+    }
+
+    // Only a domain that certainly has no address has no mail server, a
+    // failed lookup may work the next time
+    match (servers.is_empty(), error) {
+        (false, _) => Ok(servers),
+        (true, Some(e)) => Err(e),
+        (true, None) if implicit => Err(Error::NoMailServer(domain.to_string())),
+        (true, None) => Err(Error::Lookup(format!(
+            "None of the mail servers for {domain} could be resolved"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Fake {
+        mx: HashMap<&'static str, Vec<(u16, String)>>,
+        hosts: HashMap<String, Result<Vec<IpAddr>>>,
+    }
+
+    impl Lookup for Fake {
+        fn mx<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<Vec<(u16, String)>>> {
+            Box::pin(async move { Ok(self.mx.get(domain).cloned().unwrap_or_default()) })
+        }
+
+        fn addresses<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>>> {
+            Box::pin(async move { self.hosts.get(host).cloned().unwrap_or(Ok(Vec::new())) })
+        }
+    }
+
+    fn address(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    fn names(servers: &[MailServer]) -> Vec<&str> {
+        servers.iter().map(|x| x.name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn orders_by_preference() {
+        let mut fake = Fake::default();
+        fake.mx.insert(
+            "example.com",
+            vec![
+                (20, "c.example.com".to_string()),
+                (10, "a.example.com".to_string()),
+                (30, "d.example.com".to_string()),
+                (10, "b.example.com".to_string()),
+            ],
+        );
+        for (host, last) in [("a", 1), ("b", 2), ("c", 3)] {
+            let host = format!("{host}.example.com");
+            fake.hosts.insert(host, Ok(vec![address(last)]));
+        }
+        fake.hosts.insert(
+            "d.example.com".to_string(),
+            Err(Error::Lookup("SERVFAIL".to_string())),
+        );
+
+        let servers = resolve("example.com", &fake).await.unwrap();
+        let names = names(&servers);
+        assert_eq!(names.len(), 3);
+        assert!(names[..2].contains(&"a.example.com"));
+        assert!(names[..2].contains(&"b.example.com"));
+        assert_eq!(names[2], "c.example.com");
+    }
+
+    #[tokio::test]
+    async fn refuses_null_mx() {
+        let mut fake = Fake::default();
+        fake.mx.insert("example.com", vec![(0, ".".to_string())]);
+
+        let error = resolve("example.com", &fake).await.unwrap_err();
+        assert_eq!(error, Error::NullMx("example.com".to_string()));
+        assert!(error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_domain() {
+        let mut fake = Fake::default();
+        fake.hosts
+            .insert("example.com".to_string(), Ok(vec![address(1)]));
+        let servers = resolve("example.com", &fake).await.unwrap();
+        assert_eq!(
+            servers,
+            vec![MailServer {
+                name: "example.com".to_string(),
+                addresses: vec![address(1)],
+            }]
+        );
+
+        // Without addresses there is no mail server, but a failed lookup
+        // only means trying again later
+        let fake = Fake::default();
+        let error = resolve("example.com", &fake).await.unwrap_err();
+        assert_eq!(error, Error::NoMailServer("example.com".to_string()));
+        assert!(error.is_permanent());
+
+        let mut fake = Fake::default();
+        fake.hosts.insert(
+            "example.com".to_string(),
+            Err(Error::Lookup(
+                "Looking up example.com failed: SERVFAIL".to_string(),
+            )),
+        );
+        let error = resolve("example.com", &fake).await.unwrap_err();
+        assert!(!error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn keeps_address_literals() {
+        let servers = mail_servers("[IPv6:2001:db8::1]").await.unwrap();
+        assert_eq!(
+            servers[0].addresses,
+            vec!["2001:db8::1".parse::<IpAddr>().unwrap()]
+        );
+        assert!(servers[0].is_literal());
+    }
+}
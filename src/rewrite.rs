@@ -0,0 +1,231 @@
+use crate::{
+    config::{List, ReplyTo, ServerConfig},
+    message::{self, Content, Field, Header, Message, Multipart},
+    queue,
+};
+
+/// Puts `prefix` in front of the subject, unless it's already there as it is
+/// in replies.
+fn prefix_subject(message: &mut Message, prefix: &str) {
+    let subject = match message.header.get("Subject") {
+        Some(v) => v,
+        None => {
+            message
+                .header
+                .push(Field::new("Subject", &message::encode(prefix)));
+            return;
+        }
+    };
+
+    if subject
+        .text()
+        .to_lowercase()
+        .contains(&prefix.to_lowercase())
+    {
+        return;
+    }
+
+    // Encoded words in the old value stay as they were
+    let value = format!("{} {}", message::encode(prefix), subject.value());
+    message.header.set(Field::new("Subject", &value));
+}
+
+fn set_reply_to(message: &mut Message, address: &str, policy: &ReplyTo) {
+    let reply_to = match policy {
+        ReplyTo::Poster => return,
+        ReplyTo::List => address,
+        ReplyTo::Address(v) => v,
+    };
+
+    message
+        .header
+        .set(Field::new("Reply-To", &format!("<{reply_to}>")));
+}
+
+/// The footer with CRLF line endings, ending with one.
+fn footer_lines(footer: &str) -> String {
+    let mut lines: String = footer.lines().flat_map(|x| [x, "\r\n"]).collect();
+    if lines.is_empty() {
+        lines.push_str("\r\n");
+    }
+
+    lines
+}
+
+/// The footer as a MIME part of its own.
+fn footer_part(footer: &str) -> Message {
+    let mut header = Header::default();
+    header.push(Field::new("Content-Type", "text/plain; charset=utf-8"));
+    header.push(Field::new("Content-Disposition", "inline"));
+    header.push(Field::new(
+        "Content-Transfer-Encoding",
+        match footer.is_ascii() {
+            true => "7bit",
+            false => "base64",
+        },
+    ));
+
+    let mut part = Message::new(header, Content::default());
+    part.set_decoded(footer.trim_end_matches("\r\n").as_bytes());
+
+    part
+}
+
+/// Adds the footer to the end of a plain text body, if it can go there
+/// without breaking its charset.
+fn append_inline(message: &mut Message, footer: &str) -> bool {
+    let content_type = message.content_type();
+    let attachment = message
+        .header
+        .value("Content-Disposition")
+        .is_some_and(|x| x.to_ascii_lowercase().starts_with("attachment"));
+    let utf8 = content_type
+        .parameter("charset")
+        .is_some_and(|x| x.eq_ignore_ascii_case("utf-8"));
+    // 8-bit text can't go in a 7bit body
+    let encoding_fits = message.transfer_encoding() != "7bit" || footer.is_ascii();
+
+    if content_type.mime_type != "text/plain"
+        || attachment
+        || !(footer.is_ascii() || utf8)
+        || !encoding_fits
+    {
+        return false;
+    }
+    let Some(mut body) = message.decoded() else {
+        return false;
+    };
+
+    if !body.is_empty() && !body.ends_with(b"\n") {
+        body.extend(b"\r\n");
+    }
+    body.extend(footer.as_bytes());
+
+    message.set_decoded(&body)
+}
+
+/// Turns the message into a multipart/mixed one, with the old body as the
+/// first part and the footer as the second.
+fn wrap(message: &mut Message, hostname: &str, footer: &str) {
+    let boundary = format!("{}/{hostname}", queue::new_id());
+
+    let (content, other): (Vec<Field>, Vec<Field>) = message
+        .header
+        .fields
+        .drain(..)
+        .partition(|x| x.name().to_ascii_lowercase().starts_with("content-"));
+    message.header.fields = other;
+    if message.header.get("MIME-Version").is_none() {
+        message.header.push(Field::new("MIME-Version", "1.0"));
+    }
+    message.header.push(Field::new(
+        "Content-Type",
+        &format!("multipart/mixed; boundary=\"{boundary}\""),
+    ));
+
+    let original = Message::new(
+        Header { fields: content },
+        std::mem::take(&mut message.body),
+    );
+    let mut multipart = Multipart::new(&boundary);
+    multipart.push(original);
+    multipart.push(footer_part(footer));
+    message.body = Content::Multipart(multipart);
+}
+
+fn add_footer(message: &mut Message, hostname: &str, footer: &str) {
+    let footer = footer_lines(footer);
+
+    if append_inline(message, &footer) {
+        return;
+    }
+
+    // Other multiparts are wrapped, so signatures and alternatives stay intact
+    if message.content_type().mime_type == "multipart/mixed" {
+        if let Content::Multipart(multipart) = &mut message.body {
+            multipart.push(footer_part(&footer));
+            return;
+        }
+    }
+
+    wrap(message, hostname, &footer);
+}
+
+/// Adds the subject prefix, Reply-To and footer of the list.
+pub fn apply(config: &ServerConfig, address: &str, list: &List, message: &mut Message) {
+    if let Some(prefix) = &list.subject_prefix {
+        prefix_subject(message, prefix);
+    }
+    set_reply_to(message, address, &list.reply_to());
+    if let Some(footer) = &list.footer {
+        add_footer(message, &config.hostname, footer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_subjects_once() {
+        let mut message = Message::parse(b"Subject: Hej\r\n\r\nbody\r\n");
+        prefix_subject(&mut message, "[styrelsen]");
+        assert_eq!(message.header.value("Subject").unwrap(), "[styrelsen] Hej");
+
+        let mut reply =
+            Message::parse(b"Subject: Re: =?utf-8?q?=5Bstyrelsen=5D_H=C3=A4j?=\r\n\r\nbody\r\n");
+        let before = reply.to_bytes();
+        prefix_subject(&mut reply, "[styrelsen]");
+        assert_eq!(reply.to_bytes(), before);
+    }
+
+    #[test]
+    fn appends_footers_to_plain_text() {
+        let mut message = Message::parse(b"Subject: Hej\r\n\r\nbody");
+        add_footer(&mut message, "example.com", "-- \nfooter");
+
+        assert_eq!(message.body.to_bytes(), b"body\r\n-- \r\nfooter\r\n");
+
+        let mut message = Message::parse(
+            b"Content-Type: text/plain; charset=utf-8\r\n\
+              Content-Transfer-Encoding: quoted-printable\r\n\r\nH=C3=A4j\r\n",
+        );
+        add_footer(&mut message, "example.com", "Hälsningar");
+        assert_eq!(
+            message.decoded().unwrap(),
+            "Häj\r\nHälsningar\r\n".as_bytes()
+        );
+    }
+
+    #[test]
+    fn adds_footers_to_multipart_mixed() {
+        let data = b"Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+                     --b\r\nContent-Type: text/html\r\n\r\n<p>body</p>\r\n--b--\r\n";
+        let mut message = Message::parse(data);
+        add_footer(&mut message, "example.com", "footer");
+
+        let body = String::from_utf8(message.body.to_bytes()).unwrap();
+        assert!(body.ends_with(
+            "<p>body</p>\r\n--b\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Disposition: inline\r\nContent-Transfer-Encoding: 7bit\r\n\r\n\
+             footer\r\n--b--\r\n"
+        ));
+    }
+
+    #[test]
+    fn wraps_other_bodies() {
+        let data = b"Subject: Hej\r\nContent-Type: text/html\r\n\r\n<p>body</p>\r\n";
+        let mut message = Message::parse(data);
+        add_footer(&mut message, "example.com", "footer");
+
+        let content_type = message.content_type();
+        assert_eq!(content_type.mime_type, "multipart/mixed");
+        let boundary = content_type.parameter("boundary").unwrap();
+        let body = String::from_utf8(message.body.to_bytes()).unwrap();
+        assert!(body.starts_with(&format!(
+            "--{boundary}\r\nContent-Type: text/html\r\n\r\n<p>body</p>\r\n"
+        )));
+        assert!(body.ends_with(&format!("footer\r\n--{boundary}--\r\n")));
+        assert_eq!(message.header.value("Subject").unwrap(), "Hej");
+    }
+}
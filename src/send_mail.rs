@@ -1,143 +1,1212 @@
-use color_eyre::eyre::Result;
-use domain::{
-    base::{iana::Class, Name, Question, Rtype},
-    rdata::Mx,
-    resolv::StubResolver,
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use smtp_proto::{MailFrom, RcptTo, Request, Response, MAIL_BODY_8BITMIME, MAIL_SMTPUTF8};
+use tokio::{
+    net::TcpStream,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time::timeout,
 };
-use smtp_proto::{MailFrom, RcptTo, Request};
-use tokio::net::TcpStream;
 use tracing::{debug, info, warn};
 
-use crate::stream::Stream;
+use crate::{
+    config::{AuthMechanism, DeliveryOptions, ForwardingOptions, ServerConfig, TlsPolicy},
+    dane,
+    mail::{Body, Mail},
+    mta_sts::{self, Mode},
+    resolver::{self, MailServer},
+    stream::Stream,
+    tls::Requirements,
+};
+
+/// Why a delivery didn't go through, and whether it is worth trying again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryError {
+    /// A 5xx reply, the message will never be accepted as it is.
+    Permanent(String),
+    /// A 4xx reply, or the connection itself failed.
+    Transient(String),
+}
+
+impl DeliveryError {
+    /// Turns a reply that isn't a positive completion into an error.
+    fn from_response(stage: &str, response: &Response<String>) -> Self {
+        let [e0, e1, e2] = response.esc;
+        let reason = format!(
+            "{stage} rejected: {} {e0}.{e1}.{e2} {}",
+            response.code,
+            response.message.replace('\n', " ")
+        );
+
+        match response.code {
+            500..=599 => Self::Permanent(reason),
+            _ => Self::Transient(reason),
+        }
+    }
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Permanent(reason) => write!(f, "Permanent failure: {reason}"),
+            Self::Transient(reason) => write!(f, "Transient failure: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+impl From<color_eyre::Report> for DeliveryError {
+    fn from(value: color_eyre::Report) -> Self {
+        Self::Transient(value.to_string())
+    }
+}
+
+impl From<resolver::Error> for DeliveryError {
+    fn from(value: resolver::Error) -> Self {
+        match value.is_permanent() {
+            true => Self::Permanent(value.to_string()),
+            false => Self::Transient(value.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for DeliveryError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Transient(value.to_string())
+    }
+}
+
+pub type DeliveryResult = std::result::Result<(), DeliveryError>;
+
+/// How long to wait on the server during a transaction (RFC 5321 §4.5.3.2).
+/// Anything else, like RSET, gets as long as MAIL.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const DATA_INITIATION_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+const DATA_BLOCK_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+const DATA_TERMINATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Runs `future` for at most `limit`, a server that takes longer is treated
+/// like a broken connection.
+async fn within<T, E: Into<DeliveryError>>(
+    limit: Duration,
+    stage: &str,
+    future: impl Future<Output = std::result::Result<T, E>>,
+) -> std::result::Result<T, DeliveryError> {
+    match timeout(limit, future).await {
+        Ok(v) => v.map_err(Into::into),
+        Err(_) => Err(DeliveryError::Transient(format!(
+            "{stage} timed out after {} seconds",
+            limit.as_secs()
+        ))),
+    }
+}
+
+/// Sends a request and reads the reply to it, each within `limit`.
+async fn request<T: Display>(
+    stream: &mut Stream,
+    stage: &str,
+    request: Request<T>,
+    limit: Duration,
+) -> std::result::Result<Response<String>, DeliveryError> {
+    within(limit, stage, stream.send_request(request)).await?;
+    within(limit, stage, stream.recieve_response()).await
+}
+
+/// Sends a command and expects a reply in the 2xx or 3xx range back.
+async fn command<T: Display>(
+    stream: &mut Stream,
+    stage: &str,
+    request: Request<T>,
+) -> std::result::Result<Response<String>, DeliveryError> {
+    within(COMMAND_TIMEOUT, stage, stream.send_request(request)).await?;
+    expect(stream, stage).await
+}
+
+async fn expect(
+    stream: &mut Stream,
+    stage: &str,
+) -> std::result::Result<Response<String>, DeliveryError> {
+    let response = within(COMMAND_TIMEOUT, stage, stream.recieve_response()).await?;
+
+    match response.code {
+        200..=399 => Ok(response),
+        _ => Err(DeliveryError::from_response(stage, &response)),
+    }
+}
+
+/// Says goodbye, not waiting long for a server that doesn't listen.
+async fn quit(stream: &mut Stream) {
+    let _ = within(
+        COMMAND_TIMEOUT,
+        "QUIT",
+        stream.send_request::<String>(Request::Quit),
+    )
+    .await;
+}
+
+/// A single recipient of a group delivery and the envelope sender to use
+/// for it, which differs per member with VERP.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub sender: String,
+    pub to: String,
+    /// The list the recipient is a member of, which limits how many
+    /// connections the delivery may use.
+    pub list: Option<String>,
+}
+
+/// How long to wait for a mail server to greet us before trying the next.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most recipients put in one transaction, the least every server has to
+/// accept (RFC 5321 §4.5.3.1.8).
+const MAX_RECIPIENTS: usize = 100;
+
+/// Connection limits shared by every delivery.
+static LIMITS: Mutex<Option<Limits>> = Mutex::new(None);
 
-pub async fn send_group(host: &str, msg: String, members: &Vec<String>, from: &str) {
-    for recipient in members {
-        let server = match recipient.split('@').nth(1) {
-            Some(v) => v.trim_end_matches('>'),
-            None => continue,
+/// Semaphores for the configured limits, rebuilt from scratch when the
+/// configuration changes. Connections still holding permits from the old ones
+/// simply finish.
+struct Limits {
+    options: DeliveryOptions,
+    global: Arc<Semaphore>,
+    domains: HashMap<String, Arc<Semaphore>>,
+    lists: HashMap<String, Arc<Semaphore>>,
+}
+
+impl Limits {
+    fn new(options: DeliveryOptions) -> Self {
+        Self {
+            options,
+            global: Arc::new(Semaphore::new(options.max_connections())),
+            domains: HashMap::new(),
+            lists: HashMap::new(),
+        }
+    }
+}
+
+/// Waits until a connection to `server` for `list` is within every limit.
+/// The connection may stay open for as long as the permits are held.
+async fn acquire(
+    options: DeliveryOptions,
+    server: &str,
+    list: Option<&str>,
+) -> Vec<OwnedSemaphorePermit> {
+    let semaphores = {
+        let mut limits = LIMITS.lock().unwrap_or_else(|x| x.into_inner());
+        let limits = match &mut *limits {
+            Some(v) if v.options == options => v,
+            v => v.insert(Limits::new(options)),
         };
 
-        match send(
-            host,
-            &msg,
-            &recipient,
-            &from,
-            server.to_string(),
-            None,
-            server.to_string(),
-        )
-        .await
-        {
-            Ok(_) => {}
+        // Forget the semaphores nobody holds a permit from
+        limits.domains.retain(|_, x| Arc::strong_count(x) > 1);
+        limits.lists.retain(|_, x| Arc::strong_count(x) > 1);
+
+        let mut semaphores = Vec::new();
+        if let Some(list) = list {
+            semaphores.push(
+                limits
+                    .lists
+                    .entry(list.to_string())
+                    .or_insert_with(|| Arc::new(Semaphore::new(options.max_connections_per_list())))
+                    .clone(),
+            );
+        }
+        semaphores.push(
+            limits
+                .domains
+                .entry(server.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(options.max_connections_per_domain())))
+                .clone(),
+        );
+        semaphores.push(limits.global.clone());
+        semaphores
+    };
+
+    // Always in the same order, from the narrowest limit to the widest
+    let mut permits = Vec::with_capacity(semaphores.len());
+    for semaphore in semaphores {
+        if let Ok(v) = semaphore.acquire_owned().await {
+            permits.push(v);
+        }
+    }
+
+    permits
+}
+
+/// Groups `items` by key, keeping the order keys were first seen in.
+fn group<K: PartialEq>(items: impl IntoIterator<Item = (K, usize)>) -> Vec<(K, Vec<usize>)> {
+    let mut groups: Vec<(K, Vec<usize>)> = Vec::new();
+
+    for (key, index) in items {
+        match groups.iter_mut().find(|(x, _)| *x == key) {
+            Some((_, indices)) => indices.push(index),
+            None => groups.push((key, vec![index])),
+        }
+    }
+
+    groups
+}
+
+/// Where a group of recipients that are delivered together goes: members of
+/// the same list whose domains share their mail servers.
+#[derive(Debug, Clone)]
+struct Hop {
+    /// Identifies the mail servers for the connection limits.
+    key: String,
+    servers: Vec<MailServer>,
+    /// What certificates are checked against when a server is an address.
+    domain: String,
+    list: Option<String>,
+    tls: Requirements,
+    port: Option<u16>,
+    credentials: Option<Credentials>,
+}
+
+/// Recipients sharing an envelope sender, sent in one transaction.
+struct Batch {
+    sender: String,
+    to: Vec<(usize, String)>,
+}
+
+/// Sends `mail` to every member directly, returning one result per member in
+/// the same order. Members are grouped by the server their domain resolves
+/// to, and every server gets as few transactions as the envelope senders
+/// allow, spread over as many connections as the limits in `[delivery]`
+/// allow.
+pub async fn send_group(
+    config: &ServerConfig,
+    mail: &Mail,
+    members: &[Delivery],
+) -> Vec<DeliveryResult> {
+    let options = config.delivery();
+    let mut results: Vec<Option<DeliveryResult>> = vec![None; members.len()];
+
+    let mut domains = Vec::new();
+    for (i, member) in members.iter().enumerate() {
+        match member.to.split('@').nth(1) {
+            Some(v) => domains.push((v.trim_end_matches('>').to_lowercase(), i)),
+            None => {
+                results[i] = Some(Err(DeliveryError::Permanent(format!(
+                    "{} has no domain",
+                    member.to
+                ))))
+            }
+        }
+    }
+
+    let smarthost = match config
+        .forwarding
+        .clone()
+        .filter(|x| x.enable && x.relay_all())
+    {
+        Some(v) => Some(smarthost_hop(v).await),
+        None => None,
+    };
+
+    // Several domains with the same mail servers share connections
+    let mut hops: Vec<(Hop, Vec<usize>)> = Vec::new();
+    for (domain, indices) in group(domains) {
+        let hop = match &smarthost {
+            Some(v) => v.clone(),
+            None => direct_hop(config, &domain).await,
+        };
+        let hop = match hop {
+            Ok(v) => v,
+            Err(e) => {
+                for i in indices {
+                    results[i] = Some(Err(e.clone()));
+                }
+                continue;
+            }
+        };
+
+        for (list, indices) in group(indices.into_iter().map(|i| (members[i].list.clone(), i))) {
+            match hops
+                .iter_mut()
+                .find(|(x, _)| x.key == hop.key && x.list == list && x.tls == hop.tls)
+            {
+                Some((_, v)) => v.extend(indices),
+                None => hops.push((
+                    Hop {
+                        list,
+                        ..hop.clone()
+                    },
+                    indices,
+                )),
+            }
+        }
+    }
+
+    let mail = Arc::new(mail.clone());
+    let mut workers = JoinSet::new();
+
+    for (hop, indices) in hops {
+        let batches: VecDeque<Batch> = group(indices.iter().map(|&i| (&members[i].sender, i)))
+            .into_iter()
+            .flat_map(|(sender, indices)| {
+                indices
+                    .chunks(MAX_RECIPIENTS)
+                    .map(|x| Batch {
+                        sender: sender.clone(),
+                        to: x.iter().map(|&i| (i, members[i].to.clone())).collect(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let count = batches.len().min(options.max_connections_per_domain());
+        let batches = Arc::new(Mutex::new(batches));
+
+        for _ in 0..count {
+            workers.spawn(send_to_server(
+                options,
+                config.hostname.clone(),
+                mail.clone(),
+                hop.clone(),
+                batches.clone(),
+            ));
+        }
+    }
+
+    while let Some(worker) = workers.join_next().await {
+        match worker {
+            Ok(v) => {
+                for (i, result) in v {
+                    results[i] = Some(result);
+                }
+            }
             Err(_e) => {
-                warn!("Couldn't send mail to {recipient}");
+                warn!("Delivery worker failed");
                 debug!("Error: {_e}");
             }
+        }
+    }
+
+    members
+        .iter()
+        .zip(results)
+        .map(|(member, result)| {
+            let result = result.unwrap_or(Err(DeliveryError::Transient(
+                "Delivery was never attempted".to_string(),
+            )));
+            if let Err(e) = &result {
+                warn!("Couldn't send mail to {}: {e}", member.to);
+            }
+            result
+        })
+        .collect()
+}
+
+/// Applies the MTA-STS policy of `domain` to its mail servers, leaving the
+/// ones it allows and requiring verified TLS to them when it is enforced.
+async fn apply_mta_sts(
+    config: &ServerConfig,
+    domain: &str,
+    servers: Vec<MailServer>,
+    tls: &mut Requirements,
+) -> std::result::Result<Vec<MailServer>, DeliveryError> {
+    if !config.outbound_tls().mta_sts() || domain.starts_with('[') {
+        return Ok(servers);
+    }
+
+    let Some(policy) = mta_sts::policy(config, domain, config.mta_sts_fetcher().as_ref()).await
+    else {
+        return Ok(servers);
+    };
+
+    let (allowed, refused): (Vec<_>, Vec<_>) =
+        servers.into_iter().partition(|x| policy.matches(&x.name));
+
+    match policy.mode {
+        Mode::Enforce => {
+            if allowed.is_empty() {
+                return Err(DeliveryError::Transient(format!(
+                    "4.7.5 No mail server of {domain} matches its MTA-STS policy"
+                )));
+            }
+            tls.policy = TlsPolicy::Verify;
+            Ok(allowed)
+        }
+        Mode::Testing => {
+            for server in &refused {
+                warn!(
+                    "{} isn't allowed by the MTA-STS policy of {domain}, delivering anyway as it is only testing",
+                    server.name
+                );
+            }
+            Ok(allowed.into_iter().chain(refused).collect())
+        }
+        Mode::None => Ok(allowed.into_iter().chain(refused).collect()),
+    }
+}
+
+/// The mail servers of `domain`, with the TLS its policies ask for.
+async fn direct_hop(
+    config: &ServerConfig,
+    domain: &str,
+) -> std::result::Result<Hop, DeliveryError> {
+    let servers = resolver::mail_servers(domain).await?;
+
+    let mut names: Vec<&str> = servers.iter().map(|x| x.name.as_str()).collect();
+    names.sort_unstable();
+    let key = names.join(",");
+    let mut tls = Requirements::new(config.outbound_tls().policy_for(domain));
+    tls.dane = config.outbound_tls().dane();
+    let servers = apply_mta_sts(config, domain, servers, &mut tls).await?;
+
+    Ok(Hop {
+        key,
+        servers,
+        domain: domain.to_string(),
+        list: None,
+        tls,
+        port: None,
+        credentials: None,
+    })
+}
+
+/// The forwarding server, standing in for the mail servers of every domain.
+async fn smarthost_hop(forwarding: ForwardingOptions) -> std::result::Result<Hop, DeliveryError> {
+    let relay = Relay::new(forwarding).await?;
+    let servers = resolver::mail_servers(&relay.server).await?;
+
+    Ok(Hop {
+        key: relay.server,
+        servers,
+        domain: relay.tls_name,
+        list: None,
+        tls: relay.tls,
+        port: relay.port,
+        credentials: relay.credentials,
+    })
+}
+
+/// Takes batches for `hop` until there are none left, over one connection
+/// that is reopened only if it breaks.
+async fn send_to_server(
+    options: DeliveryOptions,
+    host: String,
+    mail: Arc<Mail>,
+    hop: Hop,
+    batches: Arc<Mutex<VecDeque<Batch>>>,
+) -> Vec<(usize, DeliveryResult)> {
+    let _permits = acquire(options, &hop.key, hop.list.as_deref()).await;
+    let mut results = Vec::new();
+    let mut connection: Option<(Stream, Vec<String>)> = None;
+
+    // Through a closure so the lock is released again before delivering
+    let next = || batches.lock().ok().and_then(|mut x| x.pop_front());
+
+    while let Some(batch) = next() {
+        if connection.is_none() {
+            let connection_result = connect(
+                &hop.servers,
+                hop.port,
+                &host,
+                &hop.domain,
+                &hop.tls,
+                hop.credentials.as_ref(),
+            )
+            .await;
+            match connection_result {
+                Ok(v) => connection = Some(v),
+                Err(e) => {
+                    results.extend(batch.to.iter().map(|(i, _)| (*i, Err(e.clone()))));
+                    continue;
+                }
+            }
+        }
+        let Some((stream, capabilities)) = &mut connection else {
+            continue;
         };
+
+        let to: Vec<&str> = batch.to.iter().map(|(_, x)| x.as_str()).collect();
+        let mut batch_results = vec![None; to.len()];
+
+        if let Err(e) = transaction(
+            stream,
+            capabilities,
+            &mail,
+            &batch.sender,
+            &to,
+            &mut batch_results,
+        )
+        .await
+        {
+            debug!("Dropping connection to {}: {e}", hop.key);
+            connection = None;
+            fail_pending(&mut batch_results, &e);
+        }
+
+        results.extend(
+            batch
+                .to
+                .iter()
+                .zip(batch_results)
+                .filter_map(|((i, _), result)| Some((*i, result?))),
+        );
+    }
+
+    if let Some((stream, _)) = &mut connection {
+        quit(stream).await;
     }
+
+    results
 }
 
-pub async fn send(
-    host: &str,
-    msg: &str,
-    to: &str,
-    from: &str,
-    server_override: String,
-    server_port: Option<u16>,
-    server: String,
-) -> Result<()> {
-    let stream = &mut establish_smtp_connection(server_override, server_port, host, server).await?;
+/// Runs one mail transaction to every address in `to`, filling in a result
+/// for each recipient as it is known. An `Err` means the connection can't be
+/// used anymore, recipients without a result are then left for the caller.
+async fn transaction(
+    stream: &mut Stream,
+    capabilities: &[String],
+    mail: &Mail,
+    sender: &str,
+    to: &[&str],
+    results: &mut [Option<DeliveryResult>],
+) -> DeliveryResult {
+    let mut flags = 0;
+    for (recipient, result) in to.iter().zip(results.iter_mut()) {
+        match mail_flags(mail, recipient, capabilities) {
+            Ok(v) => flags |= v,
+            Err(e) => *result = Some(Err(e)),
+        }
+    }
+
+    let pending = |results: &[Option<DeliveryResult>]| results.iter().any(|x| x.is_none());
+    if !pending(results) {
+        return Ok(());
+    }
 
-    let mut mail_from = MailFrom::default();
-    mail_from.address = from;
+    let mail_from = MailFrom {
+        address: sender,
+        flags,
+        ..Default::default()
+    };
+    let response = request(
+        stream,
+        "MAIL",
+        Request::Mail { from: mail_from },
+        COMMAND_TIMEOUT,
+    )
+    .await?;
+    if !(200..=399).contains(&response.code) {
+        let e = DeliveryError::from_response("MAIL", &response);
+        fail_pending(results, &e);
+        return command::<String>(stream, "RSET", Request::Rset)
+            .await
+            .map(|_| ());
+    }
 
-    let mut rcpt_to = RcptTo::default();
-    rcpt_to.address = to;
+    let mut accepted = Vec::new();
+    for (i, recipient) in to.iter().enumerate() {
+        if results[i].is_some() {
+            continue;
+        }
 
-    stream
-        .send_request(Request::Mail { from: mail_from })
+        let rcpt_to = RcptTo {
+            address: *recipient,
+            ..Default::default()
+        };
+        let response = request(
+            stream,
+            "RCPT",
+            Request::Rcpt { to: rcpt_to },
+            COMMAND_TIMEOUT,
+        )
         .await?;
-    let _response = stream.recieve_response().await?;
-    stream.send_request(Request::Rcpt { to: rcpt_to }).await?;
-    let _response = stream.recieve_response().await?;
-    stream.send_request::<String>(Request::Data).await?;
-    let _response = stream.recieve_response().await?;
-    stream.send_mail(msg.as_bytes()).await?;
-    let _response = stream.recieve_response().await?;
-    stream.send_request::<String>(Request::Quit).await?;
-
-    info!("Sent mail to {to}");
+
+        match response.code {
+            200..=399 => accepted.push(i),
+            _ => results[i] = Some(Err(DeliveryError::from_response("RCPT", &response))),
+        }
+    }
+
+    if accepted.is_empty() {
+        return command::<String>(stream, "RSET", Request::Rset)
+            .await
+            .map(|_| ());
+    }
+
+    let response =
+        request::<String>(stream, "DATA", Request::Data, DATA_INITIATION_TIMEOUT).await?;
+    if !(200..=399).contains(&response.code) {
+        let e = DeliveryError::from_response("DATA", &response);
+        fail_pending(results, &e);
+        return command::<String>(stream, "RSET", Request::Rset)
+            .await
+            .map(|_| ());
+    }
+
+    stream.send_mail(&mail.data, DATA_BLOCK_TIMEOUT).await?;
+    let response = within(
+        DATA_TERMINATION_TIMEOUT,
+        "End of data",
+        stream.recieve_response(),
+    )
+    .await?;
+    let result = match response.code {
+        200..=399 => {
+            info!(
+                "Sent mail to {}: {} {}",
+                accepted
+                    .iter()
+                    .map(|&i| to[i])
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                response.code,
+                response.message.replace('\n', " ")
+            );
+            Ok(())
+        }
+        _ => Err(DeliveryError::from_response("Message", &response)),
+    };
+
+    for i in accepted {
+        results[i] = Some(result.clone());
+    }
+
     Ok(())
 }
 
-async fn establish_smtp_connection(
-    server: String,
+fn fail_pending(results: &mut [Option<DeliveryResult>], e: &DeliveryError) {
+    for result in results.iter_mut().filter(|x| x.is_none()) {
+        *result = Some(Err(e.clone()));
+    }
+}
+
+/// A fixed next hop, like the forwarding server.
+#[derive(Debug, Clone)]
+pub struct Relay {
+    pub server: String,
+    pub port: Option<u16>,
+    /// What certificates are checked against when `server` is an address.
+    pub tls_name: String,
+    pub tls: Requirements,
+    pub credentials: Option<Credentials>,
+}
+
+impl Relay {
+    /// The forwarding server as configured. The OAuth token is read here, so
+    /// the newest one is always used.
+    pub async fn new(forwarding: ForwardingOptions) -> std::result::Result<Self, DeliveryError> {
+        let policy = forwarding.tls();
+        let token = match &forwarding.oauth_token_file {
+            Some(v) => Some(
+                tokio::fs::read_to_string(v)
+                    .await
+                    .map_err(|e| {
+                        DeliveryError::Transient(format!("Couldn't read OAuth token {v}: {e}"))
+                    })?
+                    .trim()
+                    .to_string(),
+            ),
+            None => forwarding.oauth_token,
+        };
+
+        let credentials = match (forwarding.username, token, forwarding.password) {
+            (Some(username), Some(token), _) => Some(Credentials {
+                username,
+                secret: token,
+                mechanism: Some(AuthMechanism::XOAuth2),
+            }),
+            (Some(username), None, Some(password)) => Some(Credentials {
+                username,
+                secret: password,
+                mechanism: forwarding.auth,
+            }),
+            _ => None,
+        };
+
+        let mut tls = Requirements {
+            policy,
+            ca_bundle: forwarding.ca_bundle.map(PathBuf::from),
+            pins: forwarding.pinned_certificates.unwrap_or_default(),
+            ..Default::default()
+        };
+        // Plaintext or an unchecked certificate would hand the password to
+        // anyone in the middle, only an explicit "None" allows that
+        if credentials.is_some()
+            && matches!(tls.policy, TlsPolicy::Opportunistic | TlsPolicy::Required)
+        {
+            tls.policy = TlsPolicy::Verify;
+        }
+
+        Ok(Self {
+            server: forwarding.server.unwrap_or(forwarding.server_tls.clone()),
+            port: forwarding.port,
+            tls_name: forwarding.server_tls,
+            tls,
+            credentials,
+        })
+    }
+}
+
+/// What we log in to a relay with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    /// The password, or the token for XOAUTH2.
+    pub secret: String,
+    pub mechanism: Option<AuthMechanism>,
+}
+
+/// Sends `mail` to a single recipient through `relay`.
+pub async fn send(host: &str, mail: &Mail, to: &str, relay: &Relay) -> DeliveryResult {
+    let servers = resolver::mail_servers(&relay.server).await?;
+    let (stream, capabilities) = &mut connect(
+        &servers,
+        relay.port,
+        host,
+        &relay.tls_name,
+        &relay.tls,
+        relay.credentials.as_ref(),
+    )
+    .await?;
+
+    let mut results = [None];
+    let result = transaction(
+        stream,
+        capabilities,
+        mail,
+        &mail.sender,
+        &[to],
+        &mut results,
+    )
+    .await;
+    if result.is_ok() {
+        quit(stream).await;
+    }
+
+    // A result for the recipient outlives a connection lost afterwards
+    results[0].clone().unwrap_or_else(|| {
+        result.and(Err(DeliveryError::Transient(
+            "Delivery was never attempted".to_string(),
+        )))
+    })
+}
+
+/// Works out the `MAIL FROM` parameters for the next hop. Declarations the
+/// message doesn't actually need are dropped; ones it does need but the next
+/// hop can't handle fail the delivery.
+fn mail_flags(
+    mail: &Mail,
+    to: &str,
+    capabilities: &[String],
+) -> std::result::Result<u64, DeliveryError> {
+    let mut flags = 0;
+
+    if mail.body == Body::EightBitMime && mail.is_8bit() {
+        if !capabilities.contains(&"8BITMIME".to_string()) {
+            return Err(DeliveryError::Permanent(format!(
+                "Next hop for {to} does not support 8BITMIME"
+            )));
+        }
+        flags |= MAIL_BODY_8BITMIME;
+    }
+
+    if mail.requires_smtputf8(to) {
+        if !capabilities.contains(&"SMTPUTF8".to_string()) {
+            return Err(DeliveryError::Permanent(format!(
+                "Next hop for {to} does not support SMTPUTF8"
+            )));
+        }
+        flags |= MAIL_SMTPUTF8;
+    }
+
+    Ok(flags)
+}
+
+/// Tries every address of every server in turn until one greets us with
+/// TLS as `requirements` want it, and gets that connection ready for mail
+/// transactions.
+async fn connect(
+    servers: &[MailServer],
     server_port: Option<u16>,
     host: &str,
-    tls: String,
-) -> Result<Stream> {
+    tls: &str,
+    requirements: &Requirements,
+    credentials: Option<&Credentials>,
+) -> std::result::Result<(Stream, Vec<String>), DeliveryError> {
     let server_port = server_port.unwrap_or(25);
+    let mut error = DeliveryError::Transient("No mail servers to connect to".to_string());
+
+    for server in servers {
+        // TLSA records are per server, there are none for address literals
+        let requirements = match requirements.dane && !server.is_literal() {
+            true => match dane::secure(requirements, &server.name, server_port).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error = DeliveryError::Transient(format!("4.7.5 {e}"));
+                    debug!("Skipping {}: {error}", server.name);
+                    continue;
+                }
+            },
+            false => requirements.clone(),
+        };
+        let config = requirements
+            .client_config()
+            .map_err(|e| DeliveryError::Transient(format!("4.7.5 Invalid TLS settings: {e}")))?;
 
-    let address = get_address(&server).await?;
-    let stream = TcpStream::connect(format!("{address}:{server_port}")).await?;
-    let mut stream = Stream::Tcp(stream);
+        for address in &server.addresses {
+            let address = SocketAddr::new(*address, server_port);
+            let tls = match server.is_literal() {
+                true if tls.starts_with('[') => address.ip().to_string(),
+                true => tls.to_string(),
+                false => server.name.trim_end_matches('.').to_string(),
+            };
 
-    let _response = stream.recieve_response().await?;
+            let stream = async {
+                let (mut stream, capabilities) =
+                    open(address, host, tls, &requirements, config.clone()).await?;
+                if let Some(credentials) = credentials {
+                    authenticate(&mut stream, &capabilities, credentials, address).await?;
+                }
+                Ok((stream, capabilities))
+            };
+            match timeout(CONNECT_TIMEOUT, stream).await {
+                Ok(Ok(v)) => return Ok(v),
+                Ok(Err(e)) => error = e,
+                Err(_) => {
+                    error = DeliveryError::Transient(format!("Connecting to {address} timed out"))
+                }
+            }
+            debug!("Couldn't connect to {} at {address}: {error}", server.name);
+        }
+    }
+
+    Err(error)
+}
+
+async fn open(
+    address: SocketAddr,
+    host: &str,
+    tls: String,
+    requirements: &Requirements,
+    config: Arc<rustls::ClientConfig>,
+) -> std::result::Result<(Stream, Vec<String>), DeliveryError> {
+    let stream = TcpStream::connect(address).await?;
+    let mut stream = Stream::new(stream);
+
+    expect(&mut stream, "Connection").await?;
     stream.send_request(Request::Ehlo { host }).await?;
 
     let capabilities = stream.recieve_capabilities().await?;
 
-    let supports_tls = capabilities.contains(&"STARTTLS".to_string());
-
-    if supports_tls {
-        debug!("Server supports tls");
-        stream.send_request::<String>(Request::StartTls).await?;
-        let _response = stream.recieve_response().await?;
+    if !requirements.tls_wanted() {
+        debug!("Not using tls with {address}");
+        return Ok((stream, capabilities));
+    }
 
-        debug!("Initiating TLS handshake");
+    let supports_tls = capabilities.contains(&"STARTTLS".to_string());
 
-        let mut stream = stream
-            .start_tls_client(
-                if address.starts_with(['0', '1', '2', '3', '4', '5', '6', '7', '8', '9']) {
-                    tls
-                } else {
-                    address
-                },
-            )
-            .await?;
+    if !supports_tls {
+        if requirements.tls_required() {
+            quit(&mut stream).await;
+            return Err(DeliveryError::Transient(format!(
+                "4.7.4 TLS is required, but {address} does not offer STARTTLS"
+            )));
+        }
 
-        stream.send_request(Request::Ehlo { host }).await?;
-        let _capabilities = stream.recieve_capabilities().await?;
-        return Ok(stream);
-    } else {
         debug!("Server does not supports tls");
-        return Ok(stream);
+        return Ok((stream, capabilities));
     }
+
+    debug!("Server supports tls");
+    command::<String>(&mut stream, "STARTTLS", Request::StartTls).await?;
+
+    debug!("Initiating TLS handshake");
+
+    let mut stream = stream.start_tls_client(tls, config).await.map_err(|e| {
+        DeliveryError::Transient(format!("4.7.5 TLS negotiation with {address} failed: {e}"))
+    })?;
+
+    stream.send_request(Request::Ehlo { host }).await?;
+    let capabilities = stream.recieve_capabilities().await?;
+    Ok((stream, capabilities))
 }
 
-async fn get_address(server: &str) -> Result<String> {
-    let ip: String;
-    if server.starts_with('[') && server.ends_with(']') {
-        let server = server
-            .trim_start_matches('[')
-            .trim_end_matches(']')
-            .to_string();
-        ip = server;
-    } else {
-        ip = lookup_mx(server).await?;
+/// Logs in with `credentials` (RFC 4954), using their mechanism or the best
+/// one offered.
+async fn authenticate(
+    stream: &mut Stream,
+    capabilities: &[String],
+    credentials: &Credentials,
+    address: SocketAddr,
+) -> std::result::Result<(), DeliveryError> {
+    let offered: Vec<String> = capabilities
+        .iter()
+        .filter_map(|x| {
+            let (keyword, mechanisms) = x.split_once(' ')?;
+            keyword.eq_ignore_ascii_case("AUTH").then_some(mechanisms)
+        })
+        .flat_map(|x| x.split_whitespace().map(|x| x.to_ascii_uppercase()))
+        .collect();
+    let offers = |x: &str| offered.iter().any(|y| y == x);
+
+    let mechanism = match credentials.mechanism {
+        Some(v) => v,
+        None if offers("PLAIN") => AuthMechanism::Plain,
+        None if offers("LOGIN") => AuthMechanism::Login,
+        None => {
+            quit(stream).await;
+            return Err(DeliveryError::Transient(format!(
+                "4.7.0 {address} offers no authentication we support"
+            )));
+        }
+    };
+
+    // The secrets are sent with send_raw, so they never end up in the log
+    let username = &credentials.username;
+    let secret = &credentials.secret;
+    let response = match mechanism {
+        AuthMechanism::Plain => {
+            let response = STANDARD.encode(format!("\0{username}\0{secret}"));
+            stream
+                .send_raw(format!("AUTH PLAIN {response}\r\n").as_bytes())
+                .await?;
+            stream.recieve_response().await?
+        }
+        AuthMechanism::Login => {
+            stream.send_raw(b"AUTH LOGIN\r\n").await?;
+            let mut response = stream.recieve_response().await?;
+            for answer in [username, secret] {
+                if response.code != 334 {
+                    break;
+                }
+                let answer = STANDARD.encode(answer);
+                stream.send_raw(format!("{answer}\r\n").as_bytes()).await?;
+                response = stream.recieve_response().await?;
+            }
+            response
+        }
+        AuthMechanism::XOAuth2 => {
+            let response =
+                STANDARD.encode(format!("user={username}\x01auth=Bearer {secret}\x01\x01"));
+            stream
+                .send_raw(format!("AUTH XOAUTH2 {response}\r\n").as_bytes())
+                .await?;
+            let mut response = stream.recieve_response().await?;
+            // The error details come as a challenge, answered with nothing
+            if response.code == 334 {
+                stream.send_raw(b"\r\n").await?;
+                response = stream.recieve_response().await?;
+            }
+            response
+        }
+    };
+
+    if response.code == 235 {
+        debug!("Authenticated with {address} as {username}");
+        return Ok(());
     }
 
-    Ok(ip)
+    quit(stream).await;
+    // Not permanent, the credentials may well be fixed before the queue
+    // gives up
+    Err(DeliveryError::Transient(format!(
+        "4.7.0 Authentication with {address} failed: {} {}",
+        response.code, response.message
+    )))
 }
 
-async fn lookup_mx(server: &str) -> Result<String> {
-    let resolver = StubResolver::new();
-    let name = Name::bytes_from_str(server)?;
-    let question = Question::new(name, Rtype::MX, Class::IN);
-    let answer = resolver.query(question).await?;
-    let answer = answer.answer()?.next().unwrap()?;
-    let answer = answer.to_record::<Mx<_>>()?.unwrap();
-    let answer = answer.data().exchange().to_string();
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
 
-    Ok(answer)
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_stalled_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(address), listener.accept());
+        let mut stream = Stream::new(client.unwrap());
+        // Accepts the connection but never answers
+        let _server = server.unwrap();
+
+        let mail = Mail {
+            sender: "<a@example.com>".to_string(),
+            recipients: vec!["b@example.com".to_string()],
+            data: b"Subject: x\r\n\r\nx\r\n".to_vec(),
+            body: Body::SevenBit,
+            smtputf8: false,
+            authenticated: None,
+        };
+        let mut results = [None];
+        let result = transaction(
+            &mut stream,
+            &[],
+            &mail,
+            &mail.sender,
+            &["b@example.com"],
+            &mut results,
+        )
+        .await;
+
+        assert_eq!(
+            result,
+            Err(DeliveryError::Transient(
+                "MAIL timed out after 300 seconds".to_string()
+            ))
+        );
+    }
+
+    /// Runs a transaction to two recipients against a server that rejects
+    /// every `stage` command and accepts everything else.
+    async fn rejected_at(stage: &'static str) -> (DeliveryResult, [Option<DeliveryResult>; 2]) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(address), listener.accept());
+        let mut stream = Stream::new(client.unwrap());
+        let mut server = Stream::new(server.unwrap().0);
+
+        tokio::spawn(async move {
+            while let Ok(request) = server.recieve_request().await {
+                let name = match request {
+                    Request::Mail { .. } => "MAIL",
+                    Request::Rcpt { .. } => "RCPT",
+                    Request::Data => "DATA",
+                    _ => "",
+                };
+                let reply = match name == stage {
+                    true => "550 5.7.1 Rejected\r\n",
+                    false => "250 2.0.0 OK\r\n",
+                };
+                if server.send_raw(reply.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mail = Mail {
+            sender: "<a@example.com>".to_string(),
+            recipients: vec!["b@example.com".to_string(), "c@example.com".to_string()],
+            data: b"Subject: x\r\n\r\nx\r\n".to_vec(),
+            body: Body::SevenBit,
+            smtputf8: false,
+            authenticated: None,
+        };
+        let mut results = [None, None];
+        let result = transaction(
+            &mut stream,
+            &[],
+            &mail,
+            &mail.sender,
+            &["b@example.com", "c@example.com"],
+            &mut results,
+        )
+        .await;
+
+        (result, results)
+    }
+
+    #[tokio::test]
+    async fn fails_recipients_on_rejections() {
+        for stage in ["MAIL", "RCPT", "DATA"] {
+            let (result, results) = rejected_at(stage).await;
+
+            assert_eq!(result, Ok(()), "{stage}");
+            for result in results {
+                assert_eq!(
+                    result,
+                    Some(Err(DeliveryError::Permanent(format!(
+                        "{stage} rejected: 550 5.7.1 Rejected"
+                    )))),
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_relays_with_credentials() {
+        let forwarding = |extra: &str| -> ForwardingOptions {
+            toml::from_str(&format!(
+                "enable = true\nserver_tls = \"example.org\"\n{extra}"
+            ))
+            .unwrap()
+        };
+        let login = "username = \"a@example.org\"\npassword = \"hunter2\"\n";
+
+        let relay = Relay::new(forwarding(login)).await.unwrap();
+        assert_eq!(relay.tls.policy, TlsPolicy::Verify);
+        let relay = Relay::new(forwarding(&format!("{login}tls = \"Required\""))).await;
+        assert_eq!(relay.unwrap().tls.policy, TlsPolicy::Verify);
+        let relay = Relay::new(forwarding(&format!("{login}tls = \"None\""))).await;
+        assert_eq!(relay.unwrap().tls.policy, TlsPolicy::None);
+        let relay = Relay::new(forwarding("tls = \"Required\"")).await;
+        assert_eq!(relay.unwrap().tls.policy, TlsPolicy::Required);
+    }
+
+    #[derive(Debug)]
+    struct Policy(&'static str);
+
+    impl mta_sts::Fetcher for Policy {
+        fn record<'a>(
+            &'a self,
+            _domain: &'a str,
+        ) -> mta_sts::BoxFuture<'a, color_eyre::Result<Option<String>>> {
+            Box::pin(async move { Ok(Some(self.0.to_string())) })
+        }
+
+        fn fetch<'a>(
+            &'a self,
+            _domain: &'a str,
+        ) -> mta_sts::BoxFuture<'a, color_eyre::Result<String>> {
+            Box::pin(async move {
+                Ok(format!(
+                    "version: STSv1\r\nmode: {}\r\nmx: mx1.example.com\r\nmax_age: 86400\r\n",
+                    self.0
+                ))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_mta_sts_policies() {
+        let spool = std::env::temp_dir().join(format!("send-mail-{}", crate::queue::new_id()));
+        let mut config: ServerConfig = toml::from_str(&format!(
+            "hostname = \"localhost\"\nplugins = []\n[lists]\n[queue]\nspool = {:?}\n",
+            spool.display().to_string()
+        ))
+        .unwrap();
+        let servers: Vec<MailServer> = ["mx2.example.com", "mx1.example.com"]
+            .into_iter()
+            .map(|name| MailServer {
+                name: name.to_string(),
+                addresses: Vec::new(),
+            })
+            .collect();
+
+        config.mta_sts_fetcher = Some(Arc::new(Policy("enforce")));
+        let mut tls = Requirements::default();
+        let allowed = apply_mta_sts(&config, "example.com", servers.clone(), &mut tls)
+            .await
+            .unwrap();
+        assert_eq!(allowed, servers[1..]);
+        assert_eq!(tls.policy, TlsPolicy::Verify);
+
+        config.mta_sts_fetcher = Some(Arc::new(Policy("testing")));
+        let mut tls = Requirements::default();
+        let allowed = apply_mta_sts(&config, "example.org", servers.clone(), &mut tls)
+            .await
+            .unwrap();
+        assert_eq!(allowed.len(), 2);
+        assert_eq!(allowed[0].name, "mx1.example.com");
+        assert_eq!(tls.policy, TlsPolicy::default());
+
+        let _ = std::fs::remove_dir_all(spool);
+    }
 }
@@ -5,37 +5,112 @@ use std::{
 };
 
 use color_eyre::eyre::eyre;
-use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
-use smtp_proto::{Request, Response};
+use rustls_pki_types::ServerName;
+use smtp_proto::{
+    response::parser::ResponseReceiver, Request, Response, MAIL_BODY_8BITMIME, MAIL_SMTPUTF8,
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
     net::TcpStream,
-    time::sleep,
+    time::timeout,
 };
 use tokio_rustls::{TlsAcceptor, TlsStream};
 use tracing::debug;
 
 use crate::AsyncStream;
 
+/// RFC 5321 §4.5.3.1.4, a command line including the CRLF.
+const MAX_COMMAND_LINE_LENGTH: usize = 512;
+/// RFC 5321 §4.5.3.1.5 allows 512 octets, but some servers send longer
+/// reply text.
+const MAX_REPLY_LINE_LENGTH: u64 = 1000;
+/// RFC 5321 §4.5.3.1.6, a text line including the CRLF.
+const MAX_TEXT_LINE_LENGTH: u64 = 1000;
+/// RFC 4954 §4, an AUTH command or response line including the CRLF.
+const MAX_AUTH_LINE_LENGTH: u64 = 12288;
+/// How much of a DATA payload is written at a time.
+const DATA_BLOCK_SIZE: usize = 64 * 1024;
+
+/// A connection with a long-lived read and write buffer, so that pipelined
+/// commands and multi-line replies are never dropped between calls.
 #[derive(Debug)]
 pub enum Stream {
-    Tcp(TcpStream),
-    Tls(TlsStream<TcpStream>),
+    Tcp(BufStream<TcpStream>),
+    Tls(Box<BufStream<TlsStream<TcpStream>>>),
 }
 
 impl Stream {
-    pub fn deref(&mut self) -> Box<&mut dyn AsyncStream> {
+    pub fn new(stream: TcpStream) -> Self {
+        Self::Tcp(BufStream::new(stream))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tls(_))
+    }
+
+    pub fn deref(&mut self) -> &mut dyn AsyncStream {
         match self {
-            Self::Tcp(stream) => Box::new(stream),
-            Self::Tls(stream) => Box::new(stream),
+            Self::Tcp(stream) => stream,
+            Self::Tls(stream) => stream.as_mut(),
         }
     }
 
+    /// Takes the underlying socket out of the buffers for a TLS handshake.
+    /// Anything still sitting in the read buffer was sent in plaintext after
+    /// STARTTLS and is thrown away on purpose (RFC 3207 §4.2).
+    fn into_tcp(self) -> Result<TcpStream> {
+        match self {
+            Self::Tcp(stream) => Ok(stream.into_inner()),
+            Self::Tls(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "tfw någon försöker starttls två gånger",
+            )),
+        }
+    }
+
+    /// Reads a line, or the first `max` bytes of it if it is longer.
+    async fn read_line_max(&mut self, max: u64) -> Result<Vec<u8>> {
+        let mut buffer: Vec<u8> = Vec::new();
+        let num_bytes_recieved = self
+            .deref()
+            .take(max)
+            .read_until(b'\n', &mut buffer)
+            .await?;
+
+        if num_bytes_recieved == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed by peer",
+            ));
+        }
+
+        Ok(buffer)
+    }
+
+    /// Throws away the rest of a line that was cut off by `read_line_max`.
+    async fn skip_line(&mut self) -> Result<()> {
+        loop {
+            let buffer = self.read_line_max(MAX_AUTH_LINE_LENGTH).await?;
+
+            if buffer.ends_with(b"\n") {
+                return Ok(());
+            }
+        }
+    }
+
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        let stream = self.deref();
+
+        stream.write_all(bytes).await?;
+        stream.flush().await?;
+
+        Ok(())
+    }
+
     pub async fn send_response<T: std::fmt::Display>(
         &mut self,
         response: Response<T>,
     ) -> Result<()> {
-        let stream = *self.deref();
         let mut response_string = Cursor::new(Vec::new());
         response.write(&mut response_string)?;
 
@@ -44,40 +119,89 @@ impl Stream {
             String::from_utf8_lossy(&response_string.clone().into_inner())
         );
 
-        stream.write_all(&response_string.into_inner()).await?;
+        self.send_raw(&response_string.into_inner()).await
+    }
 
-        Ok(())
+    /// Sends a multi-line EHLO reply, one capability per line.
+    pub async fn send_capabilities(&mut self, capabilities: &[String]) -> Result<()> {
+        let mut reply = String::new();
+
+        for (i, capability) in capabilities.iter().enumerate() {
+            let separator = if i + 1 == capabilities.len() {
+                ' '
+            } else {
+                '-'
+            };
+            reply.push_str(&format!("250{separator}{capability}\r\n"));
+        }
+
+        debug!("We are S: S: {reply}");
+
+        self.send_raw(reply.as_bytes()).await
     }
 
-    pub async fn recieve_response(&mut self) -> color_eyre::eyre::Result<String> {
-        let stream = *self.deref();
+    /// Reads the raw lines of a reply, following `250-` continuation lines
+    /// until the final `250 ` line.
+    async fn recieve_reply(&mut self) -> Result<Vec<u8>> {
+        let mut reply = Vec::new();
 
-        let mut bufreader = BufReader::new(stream);
+        loop {
+            let line = self.read_line_max(MAX_REPLY_LINE_LENGTH).await?;
 
-        let mut buffer: Vec<u8> = Vec::new();
-        let _num_bytes_recieved = bufreader.read_until(b'\n', &mut buffer).await?;
+            debug!("We are C: S: {}", String::from_utf8_lossy(&line));
 
-        let response = String::from_utf8_lossy(&buffer);
+            if !line.ends_with(b"\n") {
+                return Err(Error::new(ErrorKind::InvalidData, "Reply line too long"));
+            }
 
-        debug!("We are C: S: {response}");
+            reply.extend(&line);
 
-        Ok(response.to_string())
+            if line.get(3) != Some(&b'-') {
+                return Ok(reply);
+            }
+        }
     }
 
-    pub async fn recieve_request(&mut self) -> Result<Request<String>> {
-        let stream = *self.deref();
-
-        let mut bufreader = BufReader::new(stream);
+    /// Reads a complete, possibly multi-line, reply. The lines of the text
+    /// are joined with `\n`.
+    pub async fn recieve_response(&mut self) -> color_eyre::eyre::Result<Response<String>> {
+        let reply = self.recieve_reply().await?;
+
+        match ResponseReceiver::default().parse(&mut reply.iter()) {
+            Ok(response) => Ok(response),
+            Err(e) => Err(eyre!(
+                "Invalid reply {:?}: {e}",
+                String::from_utf8_lossy(&reply)
+            )),
+        }
+    }
 
+    pub async fn recieve_request(&mut self) -> Result<Request<String>> {
         loop {
-            let mut buffer: Vec<u8> = Vec::new();
-            let _num_bytes_recieved = bufreader.read_until(b'\n', &mut buffer).await?;
+            // AUTH may carry an initial response longer than other commands
+            let buffer = self.read_line_max(MAX_AUTH_LINE_LENGTH).await?;
+            let is_auth = buffer
+                .get(..5)
+                .is_some_and(|x| x.eq_ignore_ascii_case(b"AUTH "));
+
+            if !buffer.ends_with(b"\n") {
+                self.skip_line().await?;
+            }
+            if !buffer.ends_with(b"\n") || (!is_auth && buffer.len() > MAX_COMMAND_LINE_LENGTH) {
+                debug!("We are S: C: <line too long>");
+                self.send_raw(b"500 5.5.2 Line too long\r\n").await?;
+                continue;
+            }
 
             if buffer.trim_ascii().is_empty() {
                 continue;
             }
 
-            debug!("We are S: C: {}", String::from_utf8_lossy(&buffer));
+            // The initial response of AUTH carries the password
+            match is_auth {
+                true => debug!("We are S: C: AUTH <redacted>"),
+                false => debug!("We are S: C: {}", String::from_utf8_lossy(&buffer)),
+            }
 
             match Request::parse(&mut buffer.iter()) {
                 Ok(request) => match request {
@@ -85,54 +209,94 @@ impl Stream {
                     request => return Ok(request),
                 },
                 Err(_e) => {
-                    bufreader.write_all(b"500 5.5.0 Syntax Error\r\n").await?;
+                    self.send_raw(b"500 5.5.0 Syntax Error\r\n").await?;
                 }
             };
         }
     }
 
-    pub async fn send_request<T: std::fmt::Display>(&mut self, request: Request<T>) -> Result<()> {
-        let stream = *self.deref();
+    /// Reads a line of a SASL exchange (RFC 4954 §4), without the CRLF.
+    pub async fn recieve_line(&mut self) -> Result<String> {
+        let buffer = self.read_line_max(MAX_AUTH_LINE_LENGTH).await?;
 
+        Ok(String::from_utf8_lossy(buffer.trim_ascii()).to_string())
+    }
+
+    pub async fn send_request<T: std::fmt::Display>(&mut self, request: Request<T>) -> Result<()> {
         use Request as R;
         let request = match request {
             R::Quit => "QUIT",
             R::Data => "DATA",
             R::StartTls => "STARTTLS",
+            R::Rset => "RSET",
+            R::Noop { value } => &format!("NOOP {value}"),
             R::Ehlo { host } => &format!("EHLO {host}"),
             R::Helo { host } => &format!("HELO {host}"),
-            R::Mail { from } => &format!("MAIL FROM:{}", from.address),
-            R::Rcpt { to } => &format!("RCPT TO:{}", to.address),
+            R::Mail { from } => &{
+                let mut request = format!("MAIL FROM:{}", path(&from.address.to_string()));
+                if from.flags & MAIL_BODY_8BITMIME != 0 {
+                    request.push_str(" BODY=8BITMIME");
+                }
+                if from.flags & MAIL_SMTPUTF8 != 0 {
+                    request.push_str(" SMTPUTF8");
+                }
+                request
+            },
+            R::Rcpt { to } => &format!("RCPT TO:{}", path(&to.address.to_string())),
             _ => unimplemented!(),
         };
         let request = request.to_string() + "\r\n";
 
         debug!("We are C: C: {}", &request);
 
-        stream.write_all(&request.as_bytes()).await?;
-
-        Ok(())
+        self.send_raw(request.as_bytes()).await
     }
 
-    pub async fn recieve_mail(&mut self) -> Result<Vec<u8>> {
-        let stream = *self.deref();
+    /// Reads the DATA payload, removing the dot-stuffing and the terminating
+    /// `.` line (RFC 5321 §4.5.2). Returns `None` if the payload grows past
+    /// `max_size`, in which case the rest of it is still read off the wire but
+    /// thrown away.
+    pub async fn recieve_mail(&mut self, max_size: usize) -> Result<Option<Vec<u8>>> {
         let mut buf: Vec<u8> = Vec::new();
+        let mut too_large = false;
+        let mut at_line_start = true;
 
         loop {
-            buf.push(stream.read_u8().await?);
+            let line = self.read_line_max(MAX_TEXT_LINE_LENGTH).await?;
+            let is_end = at_line_start && line == b".\r\n";
+            let line = match at_line_start && line.starts_with(b".") {
+                true => &line[1..],
+                false => &line[..],
+            };
+            at_line_start = line.ends_with(b"\n");
 
-            if buf.ends_with(b"\r\n.\r\n") {
-                break;
+            if is_end {
+                return Ok((!too_large).then_some(buf));
             }
-        }
 
-        Ok(buf)
-    }
+            if too_large {
+                continue;
+            }
+
+            if buf.len() + line.len() > max_size {
+                debug!("Message exceeds {max_size} bytes, discarding the rest");
+                too_large = true;
+                buf = Vec::new();
+                continue;
+            }
 
-    pub async fn send_mail(&mut self, mail: &[u8]) -> Result<()> {
-        let stream = *self.deref();
+            buf.extend(line);
+        }
+    }
 
-        stream.write_all(mail).await?;
+    /// Sends a DATA payload, dot-stuffing it and adding the terminator. Every
+    /// block has to be written within `block_timeout` (RFC 5321 §4.5.3.2.5).
+    pub async fn send_mail(&mut self, mail: &[u8], block_timeout: Duration) -> Result<()> {
+        for block in stuff(mail).chunks(DATA_BLOCK_SIZE) {
+            timeout(block_timeout, self.send_raw(block))
+                .await
+                .map_err(|_| Error::new(ErrorKind::TimedOut, "Sending the message timed out"))??;
+        }
 
         Ok(())
     }
@@ -145,61 +309,44 @@ impl Stream {
         Error::new(ErrorKind::ConnectionReset, "Client Quit")
     }
 
-    pub async fn protocol_error(&mut self) -> Result<()> {
-        self.send_response(Response::new(500, 5, 5, 0, "Syntax Error"))
-            .await
+    pub async fn message_too_large(&mut self) -> Result<()> {
+        self.send_response(Response::new(
+            552,
+            5,
+            3,
+            4,
+            "Message size exceeds fixed maximum message size",
+        ))
+        .await
     }
 
-    pub async fn start_tls_server(self) -> Result<Self> {
-        let stream = match self {
-            Self::Tcp(a) => a,
-            Self::Tls(_) => {
-                return Err(Error::new(
-                    ErrorKind::Unsupported,
-                    "tfw någon försöker starttls två gånger",
-                )
-                .into())
-            }
-        };
+    pub async fn bad_sequence(&mut self) -> Result<()> {
+        self.send_response(Response::new(503, 5, 5, 1, "Bad sequence of commands"))
+            .await
+    }
 
-        let certs = CertificateDer::pem_file_iter("cert.pem")
-            .unwrap()
-            .map(|x| x.unwrap())
-            .collect::<Vec<_>>();
-        let key = PrivateKeyDer::from_pem_file("privkey.pem").unwrap();
+    pub async fn not_implemented(&mut self) -> Result<()> {
+        self.send_response(Response::new(502, 5, 5, 1, "Command not implemented"))
+            .await
+    }
 
-        let config = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .unwrap();
-        let acceptor = TlsAcceptor::from(Arc::new(config));
+    pub async fn start_tls_server(self, acceptor: TlsAcceptor) -> Result<Self> {
+        let stream = self.into_tcp()?;
 
         let stream = acceptor.accept(stream).await?;
         let stream = TlsStream::Server(stream);
 
-        return Ok(Self::Tls(stream));
+        Ok(Self::Tls(Box::new(BufStream::new(stream))))
     }
 
-    pub async fn start_tls_client(self, server_name: String) -> Result<Self> {
-        let stream = match self {
-            Self::Tcp(a) => a,
-            Self::Tls(_) => {
-                return Err(Error::new(
-                    ErrorKind::Unsupported,
-                    "tfw någon försöker starttls två gånger",
-                )
-                .into())
-            }
-        };
+    pub async fn start_tls_client(
+        self,
+        server_name: String,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<Self> {
+        let stream = self.into_tcp()?;
 
-        let root_store =
-            rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-        let config = rustls::ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-        let rc_config = Arc::new(config);
-
-        let connector = tokio_rustls::TlsConnector::from(rc_config);
+        let connector = tokio_rustls::TlsConnector::from(config);
         let dns_name = match ServerName::try_from(server_name) {
             Ok(v) => v,
             Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("{e}"))),
@@ -208,46 +355,160 @@ impl Stream {
         let stream = connector.connect(dns_name, stream).await?;
         let stream = TlsStream::Client(stream);
 
-        Ok(Self::Tls(stream))
+        Ok(Self::Tls(Box::new(BufStream::new(stream))))
     }
 
     pub async fn recieve_capabilities(&mut self) -> color_eyre::eyre::Result<Vec<String>> {
-        let stream = *self.deref();
-
         debug!("Recieving capabilties");
 
-        sleep(Duration::from_millis(10)).await;
+        let reply = self.recieve_reply().await?;
+        let response = String::from_utf8_lossy(&reply);
 
-        let mut buf = [0; 1000];
-        let _num_recieved_bytes = stream.read(&mut buf).await?;
-
-        let buf = String::from_utf8_lossy(&buf);
+        if !response.starts_with('2') {
+            return Err(eyre!("EHLO rejected: {}", response.trim_end()));
+        }
 
-        let capabilties = Self::parse_capabilties(buf.to_string())?;
+        let capabilties = Self::parse_capabilties(&response);
 
         debug!("Capabilties recieved");
 
         Ok(capabilties)
     }
 
-    fn parse_capabilties(capabilties: String) -> color_eyre::eyre::Result<Vec<String>> {
-        let mut lines = capabilties.split("\r\n");
-        lines.next(); // remove first line
+    fn parse_capabilties(response: &str) -> Vec<String> {
+        response
+            .lines()
+            .skip(1) // the first line is the greeting
+            .filter_map(|line| line.get(4..))
+            .map(|capability| capability.trim().to_string())
+            .filter(|capability| !capability.is_empty())
+            .collect()
+    }
+}
+
+/// Makes sure an address is wrapped in angle brackets exactly once, as
+/// addresses can come from lists with or without them.
+fn path(address: &str) -> String {
+    format!(
+        "<{}>",
+        address.trim_start_matches('<').trim_end_matches('>')
+    )
+}
 
-        let mut capabilties = Vec::new();
+/// Escapes every line starting with `.` and terminates the payload with
+/// `CRLF.CRLF`, making sure the last line is complete first.
+fn stuff(mail: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(mail.len() + 5);
+    let mut at_line_start = true;
 
-        for line in lines {
-            let capability: String;
-            let separator = if line.contains('-') { '-' } else { ' ' };
+    for &byte in mail {
+        if at_line_start && byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(byte);
+        at_line_start = byte == b'\n';
+    }
 
-            capability = match line.split(separator).next_back() {
-                Some(v) => v.to_string(),
-                None => return Err(eyre!(":3")),
-            };
+    if !stuffed.is_empty() && !stuffed.ends_with(b"\r\n") {
+        stuffed.extend(b"\r\n");
+    }
+    stuffed.extend(b".\r\n");
 
-            capabilties.push(capability)
+    stuffed
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn connect() -> (Stream, Stream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(TcpStream::connect(address), listener.accept());
+        (Stream::new(client.unwrap()), Stream::new(server.unwrap().0))
+    }
+
+    async fn round_trip(mail: &[u8]) -> Vec<u8> {
+        let (mut client, mut server) = connect().await;
+
+        client
+            .send_mail(mail, Duration::from_secs(5))
+            .await
+            .unwrap();
+        server.recieve_mail(usize::MAX).await.unwrap().unwrap()
+    }
+
+    #[test]
+    fn stuffs_leading_dots() {
+        assert_eq!(stuff(b"a\r\n.b\r\n..\r\n"), b"a\r\n..b\r\n...\r\n.\r\n");
+        assert_eq!(stuff(b".\r\n"), b"..\r\n.\r\n");
+        assert_eq!(stuff(b"no newline"), b"no newline\r\n.\r\n");
+        assert_eq!(stuff(b""), b".\r\n");
+    }
+
+    #[tokio::test]
+    async fn round_trips_tricky_bodies() {
+        let bodies: &[&[u8]] = &[
+            b"Hello\r\n",
+            b"",
+            b".\r\n",
+            b"..\r\n",
+            b"first\r\n.\r\nsecond\r\n",
+            b".leading\r\n..double\r\nmid.dle\r\n",
+            b"\r\n.\r\n\r\n",
+            b"trailing dot\r\n.",
+            b"\xe5\xe4\xf6 latin-1\r\n.\xff\r\n",
+        ];
+
+        for &body in bodies {
+            let mut expected = body.to_vec();
+            if !expected.is_empty() && !expected.ends_with(b"\r\n") {
+                expected.extend(b"\r\n");
+            }
+
+            assert_eq!(round_trip(body).await, expected, "{body:?}");
         }
+    }
 
-        Ok(capabilties)
+    #[tokio::test]
+    async fn round_trips_long_lines() {
+        let mut body = vec![b'x'; 3000];
+        body.extend(b"\r\n.");
+        body.extend(vec![b'.'; 1500]);
+        body.extend(b"\r\n");
+
+        assert_eq!(round_trip(&body).await, body);
+    }
+
+    #[tokio::test]
+    async fn rejects_long_command_lines() {
+        let (mut client, mut server) = connect().await;
+
+        let mut lines = format!("NOOP {}\r\n", "x".repeat(600)).into_bytes();
+        lines.extend(vec![b'y'; 20000]);
+        lines.extend(b"\r\n");
+        lines.extend(format!("AUTH PLAIN {}\r\n", "z".repeat(2000)).into_bytes());
+        client.send_raw(&lines).await.unwrap();
+
+        let request = server.recieve_request().await.unwrap();
+        assert!(matches!(request, Request::Auth { .. }));
+
+        let replies = client.recieve_reply().await.unwrap();
+        assert_eq!(replies, b"500 5.5.2 Line too long\r\n");
+        let replies = client.recieve_reply().await.unwrap();
+        assert_eq!(replies, b"500 5.5.2 Line too long\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejects_long_reply_lines() {
+        let (mut client, mut server) = connect().await;
+
+        let reply = format!("250 {}\r\n", "x".repeat(2000));
+        server.send_raw(reply.as_bytes()).await.unwrap();
+
+        assert!(client.recieve_reply().await.is_err());
     }
 }
@@ -0,0 +1,350 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::eyre::{eyre, Result};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{get_config, ServerConfig, TlsOptions, TlsPolicy},
+    dane::{self, Tlsa},
+};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: u64 = 60;
+
+static CERTIFICATES: LazyLock<Arc<Certificates>> = LazyLock::new(Default::default);
+
+/// Built once, every handshake gets the current certificates from
+/// [`CERTIFICATES`].
+static ACCEPTOR: LazyLock<TlsAcceptor> = LazyLock::new(|| {
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(CERTIFICATES.clone());
+
+    TlsAcceptor::from(Arc::new(config))
+});
+
+/// The certificates currently served, swapped out as a whole on reload.
+#[derive(Debug, Default)]
+struct Certificates(RwLock<Loaded>);
+
+#[derive(Debug, Default)]
+struct Loaded {
+    options: TlsOptions,
+    default: Option<Arc<CertifiedKey>>,
+    names: HashMap<String, Arc<CertifiedKey>>,
+    /// Every file read and when it was last modified, to notice renewals.
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.0.read().ok()?;
+
+        let by_name = client_hello.server_name().and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            let wildcard = name.split_once('.').map(|(_, x)| format!("*.{x}"));
+
+            loaded
+                .names
+                .get(&name)
+                .or_else(|| loaded.names.get(&wildcard?))
+        });
+
+        by_name.or(loaded.default.as_ref()).cloned()
+    }
+}
+
+/// The acceptor for incoming TLS, if there is a certificate to offer.
+pub fn acceptor() -> Option<TlsAcceptor> {
+    let loaded = CERTIFICATES.0.read().ok()?;
+
+    match loaded.default.is_some() || !loaded.names.is_empty() {
+        true => Some(ACCEPTOR.clone()),
+        false => None,
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+fn load_key(certificate: &Path, key: &Path) -> Result<Arc<CertifiedKey>> {
+    let certificates =
+        CertificateDer::pem_file_iter(certificate)?.collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(eyre!("No certificates in {}", certificate.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key)?;
+
+    // Building the acceptor installs the default provider if nothing else did
+    let provider = ACCEPTOR.config().crypto_provider().clone();
+
+    Ok(Arc::new(CertifiedKey::from_der(
+        certificates,
+        key,
+        &provider,
+    )?))
+}
+
+/// Reads every certificate in `options`. Nothing is replaced unless all of
+/// them load, so a half-renewed set never takes down TLS.
+pub fn load(options: &TlsOptions) -> Result<()> {
+    let mut files = Vec::new();
+
+    let default_certificate = options.certificate();
+    let default_key = options.key();
+    let default = match load_key(&default_certificate, &default_key) {
+        Ok(v) => Some(v),
+        // Without any `[tls]` section the defaults are only a guess
+        Err(_e) if options == &TlsOptions::default() => {
+            debug!("No default certificate: {_e}");
+            None
+        }
+        Err(e) => return Err(e),
+    };
+    files.push((default_certificate, None));
+    files.push((default_key, None));
+
+    let mut names = HashMap::new();
+    for sni in options.sni() {
+        let certificate = PathBuf::from(&sni.certificate);
+        let key = PathBuf::from(&sni.key);
+        let certified_key = load_key(&certificate, &key)?;
+
+        for name in &sni.names {
+            names.insert(name.to_ascii_lowercase(), certified_key.clone());
+        }
+        files.push((certificate, None));
+        files.push((key, None));
+    }
+
+    for (path, time) in &mut files {
+        *time = modified(path);
+    }
+
+    let mut loaded = CERTIFICATES
+        .0
+        .write()
+        .map_err(|_| eyre!("Certificate lock poisoned"))?;
+    *loaded = Loaded {
+        options: options.clone(),
+        default,
+        names,
+        files,
+    };
+
+    Ok(())
+}
+
+/// Whether `options` differ from what is loaded, or any file changed since.
+fn changed(options: &TlsOptions) -> bool {
+    let Ok(loaded) = CERTIFICATES.0.read() else {
+        return true;
+    };
+
+    &loaded.options != options
+        || loaded
+            .files
+            .iter()
+            .any(|(path, time)| modified(path) != *time)
+}
+
+/// Reloads the certificates whenever the files or the `[tls]` section change,
+/// e.g. after a renewal, so the daemon never has to be restarted for it.
+pub async fn watch(config_file: Option<String>, mut config: ServerConfig) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(RELOAD_INTERVAL)).await;
+
+        match get_config(config_file.as_deref()) {
+            Ok(v) => config = v,
+            Err(_e) => {
+                warn!("Couldn't reload configuration for TLS, using the old one");
+                debug!("Error: {_e}");
+            }
+        };
+
+        let options = config.tls();
+        if !changed(&options) {
+            continue;
+        }
+
+        match load(&options) {
+            Ok(_) => info!("Reloaded TLS certificates"),
+            Err(_e) => {
+                warn!("Couldn't reload TLS certificates, keeping the old ones");
+                debug!("Error: {_e}");
+            }
+        }
+    }
+}
+
+/// What a delivery needs from the TLS of the next hop.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Requirements {
+    pub policy: TlsPolicy,
+    /// CAs to trust instead of the usual roots.
+    pub ca_bundle: Option<PathBuf>,
+    /// Fingerprints of certificates to accept no matter who signed them.
+    pub pins: Vec<String>,
+    /// Whether the TLSA records of each server are looked up (RFC 7672).
+    pub dane: bool,
+    /// The usable TLSA records of the server, taking precedence over the rest.
+    pub tlsa: Vec<Tlsa>,
+}
+
+impl Requirements {
+    pub fn new(policy: TlsPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Whether a connection without TLS must be refused.
+    pub fn tls_required(&self) -> bool {
+        matches!(self.policy, TlsPolicy::Required | TlsPolicy::Verify)
+            || !self.pins.is_empty()
+            || !self.tlsa.is_empty()
+    }
+
+    /// Whether TLS should be started at all.
+    pub fn tls_wanted(&self) -> bool {
+        self.tls_required() || self.policy != TlsPolicy::None
+    }
+
+    /// The client configuration enforcing these requirements.
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>> {
+        let builder = ClientConfig::builder();
+        let provider = builder.crypto_provider().clone();
+
+        let verifier: Arc<dyn ServerCertVerifier> = if !self.tlsa.is_empty() {
+            Arc::new(dane::Verifier {
+                provider,
+                records: self.tlsa.clone(),
+            })
+        } else if !self.pins.is_empty() {
+            Arc::new(Unverified {
+                provider,
+                pins: self.pins.iter().map(|x| normalize_pin(x)).collect(),
+            })
+        } else if self.policy == TlsPolicy::Verify {
+            let roots = match &self.ca_bundle {
+                Some(v) => {
+                    let mut roots = RootCertStore::empty();
+                    for certificate in CertificateDer::pem_file_iter(v)? {
+                        roots.add(certificate?)?;
+                    }
+                    roots
+                }
+                None => RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            };
+            WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build()?
+        } else {
+            Arc::new(Unverified {
+                provider,
+                pins: Vec::new(),
+            })
+        };
+
+        Ok(Arc::new(
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(verifier)
+                .with_no_client_auth(),
+        ))
+    }
+}
+
+fn normalize_pin(pin: &str) -> String {
+    pin.chars()
+        .filter(|x| x.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// The SHA-256 fingerprint of a certificate, as used for pinning.
+pub fn fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .map(|x| format!("{x:02x}"))
+        .collect()
+}
+
+/// Encrypts without caring who is on the other end, unless the certificate is
+/// pinned. Handshake signatures are still checked.
+#[derive(Debug)]
+struct Unverified {
+    provider: Arc<CryptoProvider>,
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for Unverified {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if self.pins.is_empty() || self.pins.contains(&fingerprint(end_entity)) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        Err(rustls::Error::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure,
+        ))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}